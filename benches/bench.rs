@@ -16,16 +16,22 @@
 //! OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF OR IN
 //! CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
 
-use std::{alloc, str};
+use std::{
+	alloc::{self, GlobalAlloc, Layout}, env, fs, iter, path::PathBuf, str, sync::atomic::{AtomicBool, AtomicUsize, Ordering}
+};
 
 use all_asserts::assert_le;
 use cap::Cap;
-use crdt_testdata::{load_testing_data, TestData, TestPatch};
-use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main, Throughput};
-use otto::{crdt::Crdt, list::{List, OttoList}};
+use crdt_testdata::{load_testing_data, TestData, TestPatch, TestTxn};
+use criterion::{BatchSize, BenchmarkId, Criterion, criterion_group, Throughput};
+use diamond_types::{list::ListCRDT, AgentId};
+use hashbag::HashBag;
+use otto::{crdt::{Crdt, CrdtInstr}, list::{List, ListInstr, OttoList}, State};
+use otto_test::{channel::channel, ot_client::OtClient, ot_server::ToClient};
+use serde_json::{json, Map, Value};
 
 #[global_allocator]
-static ALLOCATOR: Cap<alloc::System> = Cap::new(alloc::System, usize::MAX);
+static ALLOCATOR: PeakAlloc = PeakAlloc { cap: Cap::new(alloc::System, usize::MAX), tracking: AtomicBool::new(false), peak: AtomicUsize::new(0) };
 
 // TODO support all datasets
 const DATASETS: &[&str] = &[
@@ -37,32 +43,226 @@ const DATASETS: &[&str] = &[
 
 const CRITERION_MIN_SAMPLE_SIZE: usize = 10;
 
+// set to write the memory and comparison reports once the benchmarks have run, as they replay every dataset again
+const REPORTS_VAR: &str = "OTTO_BENCH_REPORTS";
+const MEMORY_REPORT: &str = "memory-report.json";
+const COMPARISON_REPORT: &str = "comparison-report.json";
+
+/// `Cap` plus a high-water mark of allocated bytes, only kept up to date while a memory measurement is running so as not to slow down
+/// the timed benchmarks
+struct PeakAlloc {
+	cap: Cap<alloc::System>,
+	tracking: AtomicBool,
+	peak: AtomicUsize,
+}
+
+impl PeakAlloc {
+	fn allocated(&self) -> usize {
+		self.cap.allocated()
+	}
+	fn peak(&self) -> usize {
+		self.peak.load(Ordering::Relaxed)
+	}
+	fn start_tracking(&self) {
+		self.peak.store(self.allocated(), Ordering::Relaxed);
+		self.tracking.store(true, Ordering::Relaxed);
+	}
+	fn stop_tracking(&self) {
+		self.tracking.store(false, Ordering::Relaxed);
+	}
+	fn update_peak(&self) {
+		if self.tracking.load(Ordering::Relaxed) {
+			let _ = self.peak.fetch_max(self.allocated(), Ordering::Relaxed);
+		}
+	}
+}
+
+unsafe impl GlobalAlloc for PeakAlloc {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		let ptr = self.cap.alloc(layout);
+		self.update_peak();
+		ptr
+	}
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		self.cap.dealloc(ptr, layout);
+	}
+	unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+		let ptr = self.cap.alloc_zeroed(layout);
+		self.update_peak();
+		ptr
+	}
+	unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+		let ptr = self.cap.realloc(ptr, layout, new_size);
+		self.update_peak();
+		ptr
+	}
+}
+
 fn testing_data(name: &str) -> TestData {
 	let filename = format!("benchmark_data/{}.json.gz", name);
 	load_testing_data(&filename)
 }
 
-fn apply_doc(test_data: &TestData) -> Crdt<List<u8>> {
-	let mut doc = <Crdt<_>>::new(List::new());
+/// A document the patches of a trace can be replayed on
+trait Replay {
+	fn replay_delete(&mut self, pos: usize, span: usize);
+	fn replay_insert(&mut self, pos: usize, content: &str);
+}
+
+impl Replay for Crdt<List<u8>> {
+	fn replay_delete(&mut self, pos: usize, span: usize) {
+		for _ in 0..span {
+			let instr = self.delete(pos);
+			self.apply_(instr);
+		}
+	}
+	fn replay_insert(&mut self, pos: usize, content: &str) {
+		for (i, x) in content.bytes().enumerate() {
+			let instr = self.insert(pos + i, x);
+			self.apply_(instr);
+		}
+	}
+}
+
+/// Inserts bitwise-negated bytes, so that it never produces the same instructions as a replica replaying the same patches unmodified
+struct Negated(Crdt<List<u8>>);
+
+impl Replay for Negated {
+	fn replay_delete(&mut self, pos: usize, span: usize) {
+		self.0.replay_delete(pos, span);
+	}
+	fn replay_insert(&mut self, pos: usize, content: &str) {
+		for (i, x) in content.bytes().enumerate() {
+			let instr = self.0.insert(pos + i, !x);
+			self.0.apply_(instr);
+		}
+	}
+}
+
+/// Plain instructions against a `List<u8>`, i.e. what an OT server holds in its `pending` queue
+struct Trace {
+	doc: List<u8>,
+	instrs: Vec<ListInstr<u8>>,
+}
 
-	for txn in &test_data.txns {
+impl Trace {
+	fn new() -> Self {
+		Self { doc: List::new(), instrs: vec![] }
+	}
+	fn push(&mut self, instr: ListInstr<u8>) {
+		self.doc.apply(&instr);
+		self.instrs.push(instr);
+	}
+}
+
+impl Replay for Trace {
+	fn replay_delete(&mut self, pos: usize, span: usize) {
+		for _ in 0..span {
+			let instr = self.doc.delete(pos);
+			self.push(instr);
+		}
+	}
+	fn replay_insert(&mut self, pos: usize, content: &str) {
+		for (i, x) in content.bytes().enumerate() {
+			let instr = self.doc.insert(pos + i, x);
+			self.push(instr);
+		}
+	}
+}
+
+struct Diamond {
+	doc: ListCRDT,
+	agent: AgentId,
+}
+
+impl Diamond {
+	fn new() -> Self {
+		let mut doc = ListCRDT::new();
+		let agent = doc.get_or_create_agent_id("agent 0");
+		Self { doc, agent }
+	}
+}
+
+impl Replay for Diamond {
+	fn replay_delete(&mut self, pos: usize, span: usize) {
+		let _ = self.doc.delete_without_content(self.agent, pos..pos + span);
+	}
+	fn replay_insert(&mut self, pos: usize, content: &str) {
+		let _ = self.doc.insert(self.agent, pos, content);
+	}
+}
+
+fn replay<D: Replay>(mut doc: D, txns: &[TestTxn]) -> D {
+	for txn in txns {
 		for TestPatch(pos, del_span, ins_content) in &txn.patches {
-			for _ in 0..*del_span {
-				let instr = doc.delete(*pos);
-				doc.apply_(instr);
+			if *del_span > 0 {
+				doc.replay_delete(*pos, *del_span);
 			}
 
-			for (i, x) in ins_content.as_bytes().iter().enumerate() {
-				let instr = doc.insert(*pos + i, *x);
-				doc.apply_(instr);
+			if !ins_content.is_empty() {
+				doc.replay_insert(*pos, ins_content);
 			}
 		}
 	}
+	doc
+}
+
+fn apply_doc(test_data: &TestData) -> Crdt<List<u8>> {
+	let doc = replay(<Crdt<_>>::new(List::new()), &test_data.txns);
 	debug_assert_eq!(test_data.end_content.len(), doc.len());
 	debug_assert_eq!(test_data.end_content, doc_to_string(&doc));
 	doc
 }
 
+fn apply_diamond_doc(test_data: &TestData) -> ListCRDT {
+	let Diamond { doc, .. } = replay(Diamond::new(), &test_data.txns);
+	debug_assert_eq!(test_data.end_content, doc.branch.content.to_string());
+	doc
+}
+
+/// The same edits as [`apply_doc`], as plain instructions against a `List<u8>`
+fn trace_instrs(test_data: &TestData) -> Vec<ListInstr<u8>> {
+	let Trace { doc, instrs } = replay(Trace::new(), &test_data.txns);
+	debug_assert_eq!(test_data.end_content, doc_to_string(&doc));
+	instrs
+}
+
+/// Replays the first half of the trace on a shared base, then the second half independently on each replica, the second one
+/// [`Negated`], so that merging has to integrate every concurrent edit
+fn divergent_docs(test_data: &TestData) -> (Crdt<List<u8>>, Crdt<List<u8>>) {
+	let (prefix, suffix) = test_data.txns.split_at(test_data.txns.len() / 2);
+	let base = replay(<Crdt<_>>::new(List::new()), prefix);
+	(replay(base.clone(), suffix), replay(Negated(base), suffix).0)
+}
+
+/// Instructions in `from` that `to` hasn't seen yet
+fn missing_instrs(to: &Crdt<List<u8>>, from: &Crdt<List<u8>>) -> Vec<CrdtInstr<List<u8>>> {
+	let to: HashBag<_> = to.instrs().collect();
+	let from: HashBag<_> = from.instrs().collect();
+	from.difference(&to).flat_map(|(instr, count)| iter::repeat(instr.clone()).take(count)).collect()
+}
+
+/// An [`OtClient`] that has received the whole trace from the server, one instruction per transaction
+fn ot_client_doc(instrs: &[ListInstr<u8>], undo: bool) -> OtClient<List<u8>> {
+	let (to_client, from_server) = channel();
+	let (to_server, from_client) = channel();
+	let mut client = OtClient::new(List::new(), from_server, to_server);
+	if undo {
+		client = client.with_undo();
+	}
+	instrs.iter().for_each(|instr| to_client.send(ToClient::Txn(vec![instr.clone()])));
+	while client.try_recv_and_commit() {}
+	while from_client.try_receive().is_some() {}
+	client
+}
+
+/// The whole trace applied to a plain `List<u8>`, i.e. what an OT client would hold without any history
+fn plain_doc(instrs: &[ListInstr<u8>]) -> List<u8> {
+	let mut doc = List::new();
+	instrs.iter().for_each(|instr| doc.apply(instr));
+	doc
+}
+
 fn doc_to_string(doc: &List<u8>) -> String {
 	String::from_utf8((0..doc.len()).map(|at| doc[at]).collect::<Vec<_>>()).unwrap()
 }
@@ -89,5 +289,208 @@ fn local_benchmarks(c: &mut Criterion) {
 	}
 }
 
-criterion_group!(benches, local_benchmarks);
-criterion_main!(benches);
+fn remote_benchmarks(c: &mut Criterion) {
+	for name in DATASETS {
+		let test_data = testing_data(name);
+		let instrs: Vec<_> = apply_doc(&test_data).instrs().collect();
+		let (a, b) = divergent_docs(&test_data);
+		let missing = missing_instrs(&a, &b);
+		let pending = trace_instrs(&test_data);
+		let instr = <List<u8>>::new().insert(0, b'x');
+		println!("{name}");
+		println!("no. instructions to merge: {}", missing.len());
+		println!("no. pending instructions: {}", pending.len());
+
+		let mut group = c.benchmark_group("remote");
+		group.sample_size(CRITERION_MIN_SAMPLE_SIZE);
+		group.throughput(Throughput::Elements(instrs.len() as u64));
+		group.bench_function(BenchmarkId::new("apply", name), |b| {
+			b.iter_batched(
+				|| instrs.clone(),
+				|instrs| {
+					let mut doc = <Crdt<_>>::new(List::new());
+					doc.apply_multiple(instrs);
+					doc
+				},
+				BatchSize::LargeInput,
+			);
+		});
+		group.throughput(Throughput::Elements(missing.len() as u64));
+		group.bench_function(BenchmarkId::new("merge", name), |bench| {
+			bench.iter_batched(
+				|| (a.clone(), missing.clone()),
+				|(mut a, missing)| {
+					a.apply_multiple(missing);
+					a
+				},
+				BatchSize::LargeInput,
+			);
+		});
+		group.throughput(Throughput::Elements(pending.len() as u64));
+		group.bench_function(BenchmarkId::new("insert_and_rebase_forward", name), |b| {
+			b.iter(|| <List<u8>>::insert_and_rebase_forward(instr.clone(), &pending));
+		});
+		group.bench_function(BenchmarkId::new("ot_client", name), |b| {
+			b.iter_with_large_drop(|| ot_client_doc(&pending, false));
+		});
+		group.bench_function(BenchmarkId::new("ot_client_with_undo", name), |b| {
+			b.iter_with_large_drop(|| ot_client_doc(&pending, true));
+		});
+		group.bench_function(BenchmarkId::new("plain", name), |b| {
+			b.iter_with_large_drop(|| plain_doc(&pending));
+		});
+		group.finish();
+		println!();
+	}
+}
+
+/// Measures `f` building a document, returning the peak and steady-state (i.e. once `f` has returned) bytes allocated on top of what was
+/// allocated beforehand
+fn measure_memory<T>(f: impl FnOnce() -> T) -> (T, usize, usize) {
+	let baseline = ALLOCATOR.allocated();
+	ALLOCATOR.start_tracking();
+	let doc = f();
+	ALLOCATOR.stop_tracking();
+	(doc, ALLOCATOR.peak() - baseline, ALLOCATOR.allocated() - baseline)
+}
+
+/// Not a benchmark, so that it isn't timed: run after criterion has finished. Returns the report, to be reused by [`comparison_report`]
+fn memory_report() -> Map<String, Value> {
+	let mut report = Map::new();
+	for name in DATASETS {
+		let test_data = testing_data(name);
+		let chars = test_data.end_content.len();
+		println!("{name}");
+
+		let (doc, peak, steady) = measure_memory(|| apply_doc(&test_data));
+		let instrs = doc.instrs().len();
+		println!("otto: {steady}B steady-state, {peak}B peak, {chars} chars, {instrs} instructions");
+		drop(doc);
+		let otto = memory_stats(chars, ("instrs", instrs), peak, steady);
+
+		// diamond-types doesn't expose how many operations it retains, so its bytes are per patch of the trace instead
+		let (doc, peak, steady) = measure_memory(|| apply_diamond_doc(&test_data));
+		let patches = test_data.len();
+		println!("diamond types: {steady}B steady-state, {peak}B peak, {chars} chars, {patches} patches");
+		drop(doc);
+		let diamond = memory_stats(chars, ("patches", patches), peak, steady);
+
+		// an OtClient keeps the acknowledged document besides its own, and a Crdt of the whole history with undo, so compare both with
+		// the plain document
+		let instrs = trace_instrs(&test_data);
+		let (doc, peak, steady) = measure_memory(|| ot_client_doc(&instrs, false));
+		println!("otto ot client: {steady}B steady-state, {peak}B peak");
+		drop(doc);
+		let ot_client = memory_stats(chars, ("instrs", instrs.len()), peak, steady);
+		let (doc, peak, steady) = measure_memory(|| ot_client_doc(&instrs, true));
+		println!("otto ot client with undo: {steady}B steady-state, {peak}B peak");
+		drop(doc);
+		let ot_client_with_undo = memory_stats(chars, ("instrs", instrs.len()), peak, steady);
+		let (doc, peak, steady) = measure_memory(|| plain_doc(&instrs));
+		println!("otto plain list: {steady}B steady-state, {peak}B peak");
+		drop(doc);
+		let plain = memory_stats(chars, ("instrs", instrs.len()), peak, steady);
+
+		println!();
+		let _ = report.insert(
+			name.to_string(),
+			json!({
+				"otto": otto,
+				"otto-ot-client": ot_client,
+				"otto-ot-client-with-undo": ot_client_with_undo,
+				"otto-plain": plain,
+				"diamond-types": diamond,
+			}),
+		);
+	}
+	let path = target_dir().join(MEMORY_REPORT);
+	fs::write(&path, serde_json::to_string_pretty(&report).unwrap()).unwrap();
+	println!("memory report written to {}", path.display());
+	report
+}
+
+fn memory_stats(chars: usize, (unit, count): (&str, usize), peak: usize, steady: usize) -> Value {
+	let mut stats = json!({
+		"chars": chars,
+		"peak_bytes": peak,
+		"steady_bytes": steady,
+		"bytes_per_char": steady as f64 / chars as f64,
+	});
+	stats[unit] = json!(count);
+	stats[format!("bytes_per_{unit}")] = json!(steady as f64 / count as f64);
+	stats
+}
+
+fn comparison_benchmarks(c: &mut Criterion) {
+	for name in DATASETS {
+		let test_data = testing_data(name);
+		let mut group = c.benchmark_group("comparison");
+		group.sample_size(CRITERION_MIN_SAMPLE_SIZE);
+		group.throughput(Throughput::Elements(test_data.len() as u64));
+		group.bench_function(BenchmarkId::new("otto", name), |b| {
+			b.iter_with_large_drop(|| apply_doc(&test_data));
+		});
+		group.bench_function(BenchmarkId::new("diamond-types", name), |b| {
+			b.iter_with_large_drop(|| apply_diamond_doc(&test_data));
+		});
+		group.finish();
+	}
+}
+
+/// Mean time in nanoseconds criterion estimated for `comparison/<function>/<name>`, or `None` if it wasn't run, e.g. under `cargo test`
+fn criterion_estimate(function: &str, name: &str) -> Option<f64> {
+	let estimates = fs::read_to_string(criterion_dir().join(format!("comparison/{function}/{name}/new/estimates.json"))).ok()?;
+	serde_json::from_str::<Value>(&estimates).ok()?["mean"]["point_estimate"].as_f64()
+}
+
+/// Ratios of otto to diamond-types, from criterion's estimates for [`comparison_benchmarks`] and the measurements of [`memory_report`]
+fn comparison_report(memory: &Map<String, Value>) {
+	let mut report = Map::new();
+	for name in DATASETS {
+		let otto_nanos = criterion_estimate("otto", name);
+		let diamond_nanos = criterion_estimate("diamond-types", name);
+		let otto_bytes = memory[*name]["otto"]["steady_bytes"].as_f64().unwrap();
+		let diamond_bytes = memory[*name]["diamond-types"]["steady_bytes"].as_f64().unwrap();
+		let time_ratio = otto_nanos.zip(diamond_nanos).map(|(otto, diamond)| otto / diamond);
+		let memory_ratio = otto_bytes / diamond_bytes;
+		println!("{name}");
+		match time_ratio {
+			Some(time_ratio) => println!("otto / diamond types: {time_ratio:.2}x time, {memory_ratio:.2}x memory"),
+			None => println!("otto / diamond types: {memory_ratio:.2}x memory, no criterion estimates for time"),
+		}
+		println!();
+
+		let _ = report.insert(
+			name.to_string(),
+			json!({
+				"otto": { "mean_nanos": otto_nanos, "steady_bytes": otto_bytes },
+				"diamond-types": { "mean_nanos": diamond_nanos, "steady_bytes": diamond_bytes },
+				"time_ratio": time_ratio,
+				"memory_ratio": memory_ratio,
+			}),
+		);
+	}
+	let path = target_dir().join(COMPARISON_REPORT);
+	fs::write(&path, serde_json::to_string_pretty(&report).unwrap()).unwrap();
+	println!("comparison report written to {}", path.display());
+}
+
+fn target_dir() -> PathBuf {
+	env::var_os("CARGO_TARGET_DIR").map_or_else(|| PathBuf::from("target"), PathBuf::from)
+}
+
+/// Where criterion writes its output, resolved the same way criterion does
+fn criterion_dir() -> PathBuf {
+	env::var_os("CRITERION_HOME").map_or_else(|| target_dir().join("criterion"), PathBuf::from)
+}
+
+criterion_group!(benches, local_benchmarks, remote_benchmarks, comparison_benchmarks);
+
+fn main() {
+	benches();
+	Criterion::default().configure_from_args().final_summary();
+	if env::var_os(REPORTS_VAR).is_some() {
+		let memory = memory_report();
+		comparison_report(&memory);
+	}
+}
@@ -1,10 +1,8 @@
-use otto::{
-	crdt::{Crdt, CrdtInstr}, State, StateTest
-};
+use otto::{crdt::CrdtInstr, State, StateTest};
 use rand::{seq::IteratorRandom, Rng};
 
 use crate::{
-	channel::{Receiver, Sender}, crdt_client::CrdtClient, ot_server::{OtServer, OtServerClient}
+	channel::{Receiver, Sender}, crdt_client::CrdtClient, gen_txn, ot_server::{OtServer, OtServerClient, ToClient, ToServer}, validation::{self, Authorizer, Validator}
 };
 
 #[derive(Debug)]
@@ -14,6 +12,8 @@ where
 {
 	crdt: CrdtClient<T>,
 	ot: OtServer<T>,
+	validator: Option<Validator<T>>,
+	authorizer: Option<Authorizer<T>>,
 }
 
 impl<T> CrdtClientOtServer<T>
@@ -21,68 +21,105 @@ where
 	T: StateTest,
 {
 	pub fn new(
-		state: T, inbox: Receiver<CrdtInstr<T>>, outboxes: impl Iterator<Item = Sender<CrdtInstr<T>>>,
-		channels: impl Iterator<Item = (Sender<Option<T::Instr>>, Receiver<Option<T::Instr>>)>,
+		state: T, inbox: Receiver<Vec<CrdtInstr<T>>>, outboxes: impl Iterator<Item = Sender<Vec<CrdtInstr<T>>>>,
+		channels: impl Iterator<Item = (Sender<ToClient<T::Instr>>, Receiver<ToServer<T::Instr>>)>,
 	) -> Self {
-		Self { crdt: CrdtClient::new(state, inbox, outboxes), ot: OtServer::new(channels) }
+		Self { crdt: CrdtClient::new(state, inbox, outboxes), ot: OtServer::new(channels), validator: None, authorizer: None }
+	}
+	/// CRDT clients' edits can't be rejected, so the invariant only holds for what OT clients send
+	pub fn with_validation(mut self, validator: Validator<T>) -> Self {
+		self.validator = Some(validator);
+		self
+	}
+	pub fn with_authorization(mut self, authorizer: Authorizer<T>) -> Self {
+		self.authorizer = Some(authorizer);
+		self
 	}
 	pub fn gen_and_send(&mut self, rng: &mut impl Rng) {
-		let crdt_instr = if self.crdt.crdt.instrs().len() == 0 || rng.gen_range(0..5) != 0 {
-			let ot_instr = StateTest::gen_trivial_instr(&*self.crdt.crdt, rng).unwrap();
-			Crdt::instr_to_crdt_instr(&self.crdt.crdt, ot_instr)
+		if self.crdt.crdt.instrs().len() == 0 || rng.gen_range(0..5) != 0 {
+			let ot_txn = gen_txn(&*self.crdt.crdt, rng);
+			let crdt_txn = self.apply_ot_txn(&ot_txn);
+			self.send_to_crdt(crdt_txn);
+			self.send_to_ot(ot_txn);
 		} else {
 			let mut undos = self.crdt.crdt.instrs();
 			let undo = rng.gen_range(0..undos.len());
-			undos.nth(undo).unwrap().inverse()
-		};
-		self.crdt.outboxes.iter_mut().for_each(|outbox| outbox.send(crdt_instr.clone()));
-
-		let ot_instr = self.crdt.crdt.instr_from_crdt_instr_(crdt_instr.clone());
-		self.ot.pending.push_back(ot_instr.clone());
-		self.ot.clients.iter_mut().for_each(|OtServerClient { to_client, .. }| {
-			to_client.send(Some(ot_instr.clone()));
-		});
-
-		self.crdt.crdt.apply(crdt_instr);
+			let crdt_txn = vec![undos.nth(undo).unwrap().inverse()];
+			self.send(crdt_txn);
+		}
 	}
 	pub fn try_recv_and_commit(&mut self) -> bool {
-		let Some(crdt_instr) = self.crdt.inbox.try_receive() else { return false };
-		let ot_instr = self.crdt.crdt.instr_from_crdt_instr_(crdt_instr.clone());
-		self.ot.pending.push_back(ot_instr.clone());
-		self.ot.clients.iter_mut().for_each(|OtServerClient { to_client, .. }| {
-			to_client.send(Some(ot_instr.clone()));
-		});
-
-		self.crdt.crdt.apply(crdt_instr);
+		let Some(crdt_txn) = self.crdt.inbox.try_receive() else { return false };
+		let ot_txn = self.apply_crdt_txn(&crdt_txn);
+		self.send_to_ot(ot_txn);
 
 		true
 	}
 	pub fn try_recv_and_send(&mut self, rng: &mut impl Rng) -> bool {
 		let Some((client, OtServerClient { from_client, .. })) = self.ot.clients.iter_mut().enumerate().filter(|(_, OtServerClient { from_client, .. })| !from_client.is_empty()).choose(rng) else { return false };
-		if let Some(ot_instr) = from_client.try_receive().unwrap() {
-			let offset = self.ot.clients[client].offset;
-			let ot_instr = T::insert_and_rebase_forward(ot_instr, &self.ot.pending.make_contiguous()[offset..]);
+		match from_client.try_receive().unwrap() {
+			ToServer::Txn(ot_txn) => {
+				let ot_txn = self.ot.rebase_forward(client, ot_txn);
+				if let Err(reason) = self.check(client, &ot_txn) {
+					self.ot.pending.truncate(self.ot.pending.len() - ot_txn.len());
+					self.ot.clients[client].send(ToClient::Reject(reason), 0);
+					return true;
+				}
 
-			let crdt_instr = self.crdt.crdt.instr_to_crdt_instr(ot_instr.clone());
-			self.crdt.outboxes.iter_mut().for_each(|outbox| outbox.send(crdt_instr.clone()));
+				let crdt_txn = self.apply_ot_txn(&ot_txn);
+				self.send_to_crdt(crdt_txn);
 
-			self.ot.pending.push_back(ot_instr.clone());
-			self.ot.clients.iter_mut().enumerate().for_each(|(client_, OtServerClient { to_client, .. })| {
-				to_client.send(if client_ != client { Some(ot_instr.clone()) } else { None });
-			});
-
-			self.crdt.crdt.apply(crdt_instr);
-		} else {
-			self.ot.clients[client].offset += 1;
-			let x = self.ot.clients.iter().map(|&OtServerClient { offset, .. }| offset).min().unwrap();
-			for _ in 0..x {
-				let _ = self.ot.pending.pop_front().unwrap();
+				self.ot.clients.iter_mut().enumerate().for_each(|(client_, ot_server_client)| {
+					ot_server_client.send(if client_ != client { ToClient::Txn(ot_txn.clone()) } else { ToClient::Ack }, ot_txn.len());
+				});
 			}
-			self.ot.clients.iter_mut().for_each(|OtServerClient { offset, .. }| *offset -= x);
+			ToServer::Ack => self.ot.ack(client),
+			ToServer::Presence(cursor) => self.ot.relay_presence(client, cursor),
 		}
 		true
 	}
+	pub fn expire_presence(&mut self, client: usize) {
+		self.ot.expire_presence(client);
+	}
 	pub fn drained(&self) -> bool {
 		self.crdt.drained() && self.ot.drained()
 	}
+	fn check(&self, client: usize, ot_txn: &[T::Instr]) -> Result<(), String> {
+		if self.authorizer.is_none() && self.validator.is_none() {
+			return Ok(());
+		}
+		validation::check(&*self.crdt.crdt, client, ot_txn, self.authorizer.as_ref(), self.validator.as_ref()).map(drop)
+	}
+	fn send(&mut self, crdt_txn: Vec<CrdtInstr<T>>) {
+		let ot_txn = self.apply_crdt_txn(&crdt_txn);
+		self.send_to_crdt(crdt_txn);
+		self.send_to_ot(ot_txn);
+	}
+	fn send_to_crdt(&mut self, crdt_txn: Vec<CrdtInstr<T>>) {
+		self.crdt.outboxes.iter_mut().for_each(|outbox| outbox.send(crdt_txn.clone()));
+	}
+	fn send_to_ot(&mut self, ot_txn: Vec<T::Instr>) {
+		self.ot.pending.extend(ot_txn.iter().cloned());
+		self.ot.clients.iter_mut().for_each(|ot_server_client| ot_server_client.send(ToClient::Txn(ot_txn.clone()), ot_txn.len()));
+	}
+	fn apply_ot_txn(&mut self, ot_txn: &[T::Instr]) -> Vec<CrdtInstr<T>> {
+		ot_txn
+			.iter()
+			.map(|ot_instr| {
+				let crdt_instr = self.crdt.crdt.instr_to_crdt_instr(ot_instr.clone());
+				self.crdt.crdt.apply(crdt_instr.clone());
+				crdt_instr
+			})
+			.collect()
+	}
+	fn apply_crdt_txn(&mut self, crdt_txn: &[CrdtInstr<T>]) -> Vec<T::Instr> {
+		crdt_txn
+			.iter()
+			.map(|crdt_instr| {
+				let ot_instr = self.crdt.crdt.instr_from_crdt_instr_(crdt_instr.clone());
+				self.crdt.crdt.apply(crdt_instr.clone());
+				ot_instr
+			})
+			.collect()
+	}
 }
@@ -1,9 +1,13 @@
+use std::time::Duration;
+
 use otto::{
 	crdt::{Crdt, CrdtInstr}, State, StateTest
 };
 use rand::Rng;
 
-use crate::channel::{Receiver, Sender};
+use crate::{
+	channel::{Receiver, Sender}, gen_txn, undo::UndoManager
+};
 
 #[derive(Debug)]
 pub struct CrdtClient<T>
@@ -11,32 +15,62 @@ where
 	T: State,
 {
 	pub(crate) crdt: Crdt<T>,
-	pub(crate) inbox: Receiver<CrdtInstr<T>>,
-	pub(crate) outboxes: Vec<Sender<CrdtInstr<T>>>,
+	pub(crate) inbox: Receiver<Vec<CrdtInstr<T>>>,
+	pub(crate) outboxes: Vec<Sender<Vec<CrdtInstr<T>>>>,
+	pub(crate) undo_manager: UndoManager<T>,
 }
 
 impl<T> CrdtClient<T>
 where
 	T: StateTest,
 {
-	pub fn new(state: T, inbox: Receiver<CrdtInstr<T>>, outboxes: impl Iterator<Item = Sender<CrdtInstr<T>>>) -> Self {
-		Self { crdt: Crdt::new(state), inbox, outboxes: outboxes.collect() }
+	pub fn new(state: T, inbox: Receiver<Vec<CrdtInstr<T>>>, outboxes: impl Iterator<Item = Sender<Vec<CrdtInstr<T>>>>) -> Self {
+		Self { crdt: Crdt::new(state), inbox, outboxes: outboxes.collect(), undo_manager: UndoManager::default() }
+	}
+	pub fn with_capture_timeout(mut self, capture_timeout: Duration) -> Self {
+		self.undo_manager = UndoManager::new(capture_timeout);
+		self
 	}
 	pub fn gen_and_send(&mut self, rng: &mut impl Rng) {
-		let instr = if self.crdt.instrs().len() == 0 || rng.gen_range(0..5) != 0 {
-			let instr = StateTest::gen_trivial_instr(&*self.crdt, rng).unwrap();
-			Crdt::instr_to_crdt_instr(&self.crdt, instr)
+		if self.crdt.instrs().len() == 0 || rng.gen_range(0..5) != 0 {
+			let txn = gen_txn(&*self.crdt, rng);
+			self.apply_and_send(txn);
 		} else {
 			let mut undos = self.crdt.instrs();
 			let undo = rng.gen_range(0..undos.len());
-			undos.nth(undo).unwrap().inverse()
-		};
-		self.crdt.apply(instr.clone());
-		self.outboxes.iter_mut().for_each(|outbox| outbox.send(instr.clone()));
+			let instr = undos.nth(undo).unwrap().inverse();
+			self.undo_manager.record(instr.clone());
+			self.send(vec![instr]);
+		}
+	}
+	pub fn apply_and_send(&mut self, txn: Vec<T::Instr>) {
+		let txn: Vec<_> = txn
+			.into_iter()
+			.map(|instr| {
+				let instr = self.crdt.instr_to_crdt_instr(instr);
+				self.crdt.apply(instr.clone());
+				instr
+			})
+			.collect();
+		self.undo_manager.record_unit(txn.clone());
+		self.outboxes.iter_mut().for_each(|outbox| outbox.send(txn.clone()));
+	}
+	pub fn undo_and_send(&mut self) -> bool {
+		let Some(txn) = self.undo_manager.undo() else { return false };
+		self.send(txn);
+		true
+	}
+	pub fn stop_capturing(&mut self) {
+		self.undo_manager.stop_capturing();
+	}
+	pub fn redo_and_send(&mut self) -> bool {
+		let Some(txn) = self.undo_manager.redo() else { return false };
+		self.send(txn);
+		true
 	}
 	pub fn try_recv_and_commit(&mut self) -> bool {
-		if let Some(instr) = self.inbox.try_receive() {
-			self.crdt.apply(instr);
+		if let Some(txn) = self.inbox.try_receive() {
+			self.crdt.apply_multiple(txn);
 			true
 		} else {
 			false
@@ -48,4 +82,8 @@ where
 	pub fn drained(&self) -> bool {
 		self.inbox.is_empty()
 	}
+	fn send(&mut self, txn: Vec<CrdtInstr<T>>) {
+		self.crdt.apply_multiple(txn.clone());
+		self.outboxes.iter_mut().for_each(|outbox| outbox.send(txn.clone()));
+	}
 }
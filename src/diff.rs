@@ -0,0 +1,240 @@
+//! Instructions turning one state into another, e.g. to send an edited copy of a document through an `OtClient` or `Crdt`.
+
+use std::iter;
+
+use otto::{
+	list::{List, ListInstr, OttoList}, map::{Map, OttoMap}, mappable_register::{MappableRegister, OttoMappableRegister}, set::{OttoSet, Set}, tuple::OttoTuple2, State
+};
+
+/// A state that can work out the instructions turning it into another
+pub trait Diff: State {
+	/// Instructions turning `self` into `new`, each applicable after the ones before it, or `None` if `self` can only be replaced as a
+	/// whole, e.g. by the list containing it
+	fn diff(&self, new: &Self) -> Option<Vec<Self::Instr>>;
+}
+
+macro_rules! impl_diff_by_eq {
+	($($t:ty)*) => {$(
+		impl Diff for $t {
+			fn diff(&self, new: &Self) -> Option<Vec<Self::Instr>> {
+				(self == new).then(Vec::new)
+			}
+		}
+	)*};
+}
+impl_diff_by_eq!(bool char f32 f64 u8 u16 u32 u64 u128 usize i8 i16 i32 i64 i128 isize);
+
+impl<T> Diff for MappableRegister<T>
+where
+	T: State,
+	Self: PartialEq,
+{
+	fn diff(&self, new: &Self) -> Option<Vec<Self::Instr>> {
+		Some(if self == new { Vec::new() } else { vec![self.set(T::clone(new))] })
+	}
+}
+
+impl<A, B> Diff for (A, B)
+where
+	A: Diff,
+	B: Diff,
+{
+	fn diff(&self, new: &Self) -> Option<Vec<Self::Instr>> {
+		let mut state = self.clone();
+		let mut instrs = Vec::new();
+		for sub_instr in self.0.diff(&new.0)? {
+			let instr = state.map_a(sub_instr);
+			push(&mut state, &mut instrs, instr);
+		}
+		for sub_instr in self.1.diff(&new.1)? {
+			let instr = state.map_b(sub_instr);
+			push(&mut state, &mut instrs, instr);
+		}
+		Some(instrs)
+	}
+}
+
+impl<T> Diff for Set<T>
+where
+	T: State + Clone + Ord,
+{
+	fn diff(&self, new: &Self) -> Option<Vec<Self::Instr>> {
+		let mut state = self.clone();
+		let mut instrs = Vec::new();
+		for value in self.iter().filter(|&value| !new.contains(value)) {
+			let instr = state.delete(value.clone());
+			push(&mut state, &mut instrs, instr);
+		}
+		for value in new.iter().filter(|&value| !self.contains(value)) {
+			let instr = state.insert(value.clone());
+			push(&mut state, &mut instrs, instr);
+		}
+		Some(instrs)
+	}
+}
+
+/// Values under the same key are diffed in place where they can be, so that concurrent edits to them survive
+impl<K, V> Diff for Map<K, V>
+where
+	K: State + Clone + Ord,
+	V: Diff + Clone + PartialEq,
+{
+	fn diff(&self, new: &Self) -> Option<Vec<Self::Instr>> {
+		let mut state = self.clone();
+		let mut instrs = Vec::new();
+		for (key, value) in self.iter() {
+			let Some(new_value) = new.get(key) else {
+				let instr = state.delete(key.clone());
+				push(&mut state, &mut instrs, instr);
+				continue;
+			};
+			if value == new_value {
+				continue;
+			}
+			if let Some(sub_instrs) = value.diff(new_value) {
+				for sub_instr in sub_instrs {
+					let instr = state.map_at(key.clone(), sub_instr);
+					push(&mut state, &mut instrs, instr);
+				}
+			} else {
+				let instr = state.insert(key.clone(), new_value.clone());
+				push(&mut state, &mut instrs, instr);
+			}
+		}
+		for (key, value) in new.iter().filter(|&(key, _)| self.get(key).is_none()) {
+			let instr = state.insert(key.clone(), value.clone());
+			push(&mut state, &mut instrs, instr);
+		}
+		Some(instrs)
+	}
+}
+
+impl<T> Diff for List<T>
+where
+	T: Diff + Clone + PartialEq,
+{
+	fn diff(&self, new: &Self) -> Option<Vec<Self::Instr>> {
+		Some(diff(self, new))
+	}
+}
+
+/// Instructions turning `old` into `new`, each applicable after the ones before it. Elements are kept along a shortest edit script, so
+/// that concurrent edits to them survive. A deleted element followed by an inserted one is edited in place where it can be diffed into
+/// it, and the rest are deleted or inserted.
+pub fn diff<T>(old: &List<T>, new: &List<T>) -> Vec<ListInstr<T>>
+where
+	T: Diff + Clone + PartialEq,
+{
+	let old_ = (0..old.len()).map(|at| &old[at]).collect::<Vec<_>>();
+	let new_ = (0..new.len()).map(|at| &new[at]).collect::<Vec<_>>();
+	let prefix = old_.iter().zip(&new_).take_while(|(a, b)| a == b).count();
+	let suffix = old_[prefix..].iter().rev().zip(new_[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+	let edits = myers(&old_[prefix..old_.len() - suffix], &new_[prefix..new_.len() - suffix]);
+
+	let mut state = old.clone();
+	let mut instrs = Vec::new();
+	let (mut i, mut j, mut at) = (prefix, prefix, prefix);
+	let mut edits = edits.into_iter().peekable();
+	while let Some(edit) = edits.next() {
+		if edit == Edit::Keep {
+			i += 1;
+			j += 1;
+			at += 1;
+			continue;
+		}
+		let (mut deletes, mut inserts) = (0, 0);
+		for edit in iter::once(edit).chain(iter::from_fn(|| edits.next_if(|&edit| edit != Edit::Keep))) {
+			match edit {
+				Edit::Delete => deletes += 1,
+				Edit::Insert => inserts += 1,
+				Edit::Keep => unreachable!(),
+			}
+		}
+		for (&old_elem, &new_elem) in old_[i..i + deletes].iter().zip(&new_[j..j + inserts]) {
+			if let Some(sub_instrs) = old_elem.diff(new_elem) {
+				for sub_instr in sub_instrs {
+					let instr = state.map_at(at, sub_instr);
+					push(&mut state, &mut instrs, instr);
+				}
+			} else {
+				let instr = state.delete(at);
+				push(&mut state, &mut instrs, instr);
+				let instr = state.insert(at, new_elem.clone());
+				push(&mut state, &mut instrs, instr);
+			}
+			at += 1;
+		}
+		for _ in inserts..deletes {
+			let instr = state.delete(at);
+			push(&mut state, &mut instrs, instr);
+		}
+		for &new_elem in &new_[j + deletes.min(inserts)..j + inserts] {
+			let instr = state.insert(at, new_elem.clone());
+			push(&mut state, &mut instrs, instr);
+			at += 1;
+		}
+		i += deletes;
+		j += inserts;
+	}
+	instrs
+}
+
+fn push<T: State>(state: &mut T, instrs: &mut Vec<T::Instr>, instr: T::Instr) {
+	state.apply(&instr);
+	instrs.push(instr);
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Edit {
+	Keep,
+	Delete,
+	Insert,
+}
+
+/// Myers' shortest edit script turning `old` into `new`, in O((n + m) d) time and O(d²) space for d deletions and insertions
+fn myers<T: PartialEq>(old: &[T], new: &[T]) -> Vec<Edit> {
+	let (n, m) = (old.len(), new.len());
+	// the furthest x reached along each diagonal k = x - y, stored at k + offset as k can be negative
+	let offset = n + m + 1;
+	let mut v = vec![0; 2 * offset + 1];
+	// the diagonals around those reachable with d edits, as of before making the d-th, to trace the path back
+	let mut trace = Vec::new();
+	'search: for d in 0..=n + m {
+		trace.push(v[offset - d - 1..=offset + d + 1].to_vec());
+		for k in (offset - d..=offset + d).step_by(2) {
+			let mut x = if k == offset - d || (k != offset + d && v[k - 1] < v[k + 1]) { v[k + 1] } else { v[k - 1] + 1 };
+			let mut y = x + offset - k;
+			while x < n && y < m && old[x] == new[y] {
+				x += 1;
+				y += 1;
+			}
+			v[k] = x;
+			if x >= n && y >= m {
+				break 'search;
+			}
+		}
+	}
+
+	let mut edits = Vec::new();
+	let (mut x, mut y) = (n, m);
+	for (d, v) in trace.iter().enumerate().rev() {
+		if d == 0 {
+			edits.extend(iter::repeat(Edit::Keep).take(x));
+			break;
+		}
+		let k = x + offset - y;
+		let at = |k: usize| k + d + 1 - offset;
+		let prev_k = if k == offset - d || (k != offset + d && v[at(k - 1)] < v[at(k + 1)]) { k + 1 } else { k - 1 };
+		let prev_x = v[at(prev_k)];
+		let prev_y = prev_x + offset - prev_k;
+		while x > prev_x && y > prev_y {
+			edits.push(Edit::Keep);
+			x -= 1;
+			y -= 1;
+		}
+		edits.push(if x == prev_x { Edit::Insert } else { Edit::Delete });
+		(x, y) = (prev_x, prev_y);
+	}
+	edits.reverse();
+	edits
+}
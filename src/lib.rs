@@ -1,8 +1,30 @@
 #![feature(let_else)]
 #![allow(clippy::if_not_else)]
 
+use otto::{State, StateTest};
+use rand::Rng;
+
 pub mod bridge;
 pub mod channel;
 pub mod crdt_client;
+pub mod diff;
+pub mod migration;
+pub mod ordered_map;
 pub mod ot_client;
 pub mod ot_server;
+pub mod undo;
+pub mod validation;
+
+const MAX_TXN_LEN: usize = 3;
+
+/// Generates a transaction of random instructions, each applicable to `state` after the ones before it
+pub(crate) fn gen_txn<T: StateTest>(state: &T, rng: &mut impl Rng) -> Vec<T::Instr> {
+	let mut state = state.clone();
+	(0..rng.gen_range(1..=MAX_TXN_LEN))
+		.map(|_| {
+			let instr = StateTest::gen_trivial_instr(&state, rng).unwrap();
+			state.apply(&instr);
+			instr
+		})
+		.collect()
+}
@@ -0,0 +1,180 @@
+//! Schema migrations, upgrading stored documents and instructions, and letting clients on an old schema keep syncing with an
+//! [`OtServer`](crate::ot_server::OtServer) on a newer one.
+
+use std::{iter, marker::PhantomData};
+
+use otto::{tuple::OttoTuple2, State, StateTest};
+
+use crate::{
+	channel::{channel, Receiver, Sender}, diff::Diff, ot_client::OtClient, ot_server::{OtServer, ToClient, ToServer}
+};
+
+/// A change of schema from `Old` to `New`, e.g. adding, renaming or retyping fields of a `#[derive(State)]` struct.
+///
+/// Upgrading has to commute with applying and with rebasing, i.e. downgrading `new` with an upgraded instruction applied gives
+/// `downgrade(new)` with the instruction applied, and upgrading a rebased instruction gives the same as rebasing the upgraded one.
+pub trait Migration {
+	type Old: State;
+	type New: State;
+	/// Upgrades a stored snapshot
+	fn upgrade(old: Self::Old) -> Self::New;
+	/// Projects a document onto the parts `Old` has, i.e. what clients on `Old` see of it
+	fn downgrade(new: Self::New) -> Self::Old;
+	/// Upgrades an instruction applicable to `downgrade(new)` into one applicable to `new`, e.g. one in flight from an old client
+	fn upgrade_instr(new: &Self::New, instr: <Self::Old as State>::Instr) -> <Self::New as State>::Instr;
+}
+
+/// Migrates over `A` then `B`, so that documents from any old version can be upgraded to the latest one
+#[derive(Debug)]
+pub struct Chain<A, B>(PhantomData<(A, B)>);
+
+impl<A, B> Migration for Chain<A, B>
+where
+	A: Migration,
+	B: Migration<Old = A::New>,
+{
+	type Old = A::Old;
+	type New = B::New;
+	fn upgrade(old: Self::Old) -> Self::New {
+		B::upgrade(A::upgrade(old))
+	}
+	fn downgrade(new: Self::New) -> Self::Old {
+		A::downgrade(B::downgrade(new))
+	}
+	fn upgrade_instr(new: &Self::New, instr: <Self::Old as State>::Instr) -> <Self::New as State>::Instr {
+		let middle = B::downgrade(new.clone());
+		B::upgrade_instr(new, A::upgrade_instr(&middle, instr))
+	}
+}
+
+/// Adds a field `F`, starting out as `F::default()`, next to a document `T`. Old clients see only `T`
+#[derive(Debug)]
+pub struct AddField<T, F>(PhantomData<(T, F)>);
+
+impl<T, F> Migration for AddField<T, F>
+where
+	T: State,
+	F: State + Default,
+{
+	type Old = T;
+	type New = (T, F);
+	fn upgrade(old: T) -> (T, F) {
+		(old, F::default())
+	}
+	fn downgrade((old, _field): (T, F)) -> T {
+		old
+	}
+	fn upgrade_instr(new: &(T, F), instr: T::Instr) -> <(T, F) as State>::Instr {
+		new.map_a(instr)
+	}
+}
+
+/// Sits between an `OtClient` on the old schema and an [`OtServer`] on the new one. Towards the server it is a client on the new schema,
+/// and towards the old client a server on the old one, so that each side only rebases over instructions of its own schema. The server's
+/// changes reach the old client as the [`Diff`] they make to the downgraded document.
+///
+/// The old client's transactions are acknowledged once upgraded, so one the server rejects is reverted on the old client like a peer's
+/// edit. A peer's cursor is relayed if it marks a place in the old schema, up to equal neighbouring elements, and the client keeps the
+/// peer's previous cursor otherwise.
+#[derive(Debug)]
+pub struct MigrationRelay<M>
+where
+	M: Migration,
+{
+	// the old client's server, which the server's changes are appended to as if from another client
+	downstream: OtServer<M::Old>,
+	// the document on the new schema, fed each message from the server once the relay has looked at it
+	upstream: OtClient<M::New>,
+	from_server: Receiver<ToClient<<M::New as State>::Instr>>,
+	to_upstream: Sender<ToClient<<M::New as State>::Instr>>,
+}
+
+impl<M> MigrationRelay<M>
+where
+	M: Migration,
+	M::Old: Diff,
+	M::New: StateTest,
+{
+	/// `state` is the document as the server has it, and the old client has to start out with `M::downgrade(state)`
+	pub fn new(
+		state: M::New, (to_client, from_client): (Sender<ToClient<<M::Old as State>::Instr>>, Receiver<ToServer<<M::Old as State>::Instr>>),
+		from_server: Receiver<ToClient<<M::New as State>::Instr>>, to_server: Sender<ToServer<<M::New as State>::Instr>>,
+	) -> Self {
+		let (to_upstream, from_relay) = channel();
+		Self {
+			downstream: OtServer::new(iter::once((to_client, from_client))),
+			upstream: OtClient::new(state, from_relay, to_server),
+			from_server,
+			to_upstream,
+		}
+	}
+	/// Upgrades and forwards the oldest message from the client
+	pub fn try_relay_to_server(&mut self) -> bool {
+		let Some(msg) = self.downstream.clients[0].from_client.try_receive() else { return false };
+		match msg {
+			ToServer::Txn(txn) => {
+				let txn = self.downstream.rebase_forward(0, txn);
+				let len = txn.len();
+				let mut state = self.upstream.state().clone();
+				let txn = txn
+					.into_iter()
+					.map(|instr| {
+						let instr = M::upgrade_instr(&state, instr);
+						state.apply(&instr);
+						instr
+					})
+					.collect();
+				self.upstream.apply_and_send(txn);
+				self.downstream.clients[0].send(ToClient::Ack, len);
+			}
+			ToServer::Ack => self.downstream.ack(0),
+			ToServer::Presence(cursor) => {
+				let cursor = cursor.map(|cursor| M::upgrade_instr(self.upstream.state(), self.downstream.rebase_cursor(0, cursor)));
+				self.upstream.set_cursor_and_send(cursor);
+			}
+		}
+		true
+	}
+	/// Applies the oldest message from the server, and forwards what it changes of the downgraded document to the client
+	pub fn try_relay_to_client(&mut self) -> bool {
+		let Some(msg) = self.from_server.try_receive() else { return false };
+		let old = matches!(msg, ToClient::Txn(_) | ToClient::Reject(_)).then(|| M::downgrade(self.upstream.state().clone()));
+		let presence = if let ToClient::Presence(client, cursor) = &msg { Some((*client, cursor.is_some())) } else { None };
+		self.to_upstream.send(msg);
+		assert!(self.upstream.try_recv_and_commit());
+		if let Some(old) = old {
+			let txn = old.diff(&M::downgrade(self.upstream.state().clone())).expect("the old schema's document can't be replaced as a whole");
+			if !txn.is_empty() {
+				self.downstream.pending.extend(txn.iter().cloned());
+				let len = txn.len();
+				self.downstream.clients[0].send(ToClient::Txn(txn), len);
+			}
+		}
+		match presence {
+			Some((client, false)) => self.downstream.clients[0].send(ToClient::Presence(client, None), 0),
+			Some((client, true)) => {
+				if let Some(cursor) = self.downgrade_cursor(client) {
+					self.downstream.clients[0].send(ToClient::Presence(client, Some(cursor)), 0);
+				}
+			}
+			None => (),
+		}
+		true
+	}
+	/// Reasons the server gave for rejecting the old client's transactions, oldest first
+	pub fn rejections(&self) -> &[String] {
+		self.upstream.rejections()
+	}
+	pub fn drained(&self) -> bool {
+		self.from_server.is_empty() && self.downstream.clients[0].from_client.is_empty() && self.upstream.drained() && self.downstream.drained()
+	}
+	// the single instruction `client`'s cursor makes to the downgraded document, if it makes exactly one
+	fn downgrade_cursor(&self, client: usize) -> Option<<M::Old as State>::Instr> {
+		let cursor = self.upstream.cursors().get(&client)?;
+		let mut state = self.upstream.state().clone();
+		let old = M::downgrade(state.clone());
+		state.apply(cursor);
+		let mut instrs = old.diff(&M::downgrade(state))?;
+		if instrs.len() == 1 { instrs.pop() } else { None }
+	}
+}
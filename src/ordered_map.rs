@@ -0,0 +1,101 @@
+//! A map whose entries keep an order of their own, e.g. the fields of a form, as a [`List`] of keys next to a [`Map`] of values.
+
+use std::collections::BTreeSet;
+
+use otto::{
+	list::{List, OttoList}, map::{Map, OttoMap}, tuple::OttoTuple2, State
+};
+
+/// Keys are ordered by where they first appear in the list, so that reordering an entry only moves its key and concurrent edits to its
+/// value survive. A key moved by two peers at once appears twice, and a key moved while another peer deletes the entry is left without
+/// a value. Reading skips both, and lists values whose key is missing from the list last, by key
+pub type OrderedMap<K, V> = (List<K>, Map<K, V>);
+
+pub trait OttoOrderedMap<K, V: State>: State {
+	/// Instructions inserting `value` under `key` so that it ends up at `at`, moving it there if `key` is already in use
+	fn insert_at(&self, at: usize, key: K, value: V) -> Vec<Self::Instr>;
+	/// Instructions moving the entry under `key` to `to`
+	fn reorder(&self, key: &K, to: usize) -> Vec<Self::Instr>;
+	fn delete(&self, key: &K) -> Vec<Self::Instr>;
+	fn map_at(&self, key: K, instr: V::Instr) -> Self::Instr;
+	fn keys(&self) -> Vec<&K>;
+	fn get(&self, key: &K) -> Option<&V>;
+}
+
+impl<K, V> OttoOrderedMap<K, V> for OrderedMap<K, V>
+where
+	K: State + Clone + Ord,
+	V: State + Clone,
+{
+	fn insert_at(&self, at: usize, key: K, value: V) -> Vec<Self::Instr> {
+		let mut map = self.clone();
+		let mut txn = Vec::new();
+		move_to(&mut map, &key, at, &mut txn);
+		txn.push(map.map_b(map.1.insert(key, value)));
+		txn
+	}
+	fn reorder(&self, key: &K, to: usize) -> Vec<Self::Instr> {
+		assert!(self.get(key).is_some());
+		let mut map = self.clone();
+		let mut txn = Vec::new();
+		move_to(&mut map, key, to, &mut txn);
+		txn
+	}
+	fn delete(&self, key: &K) -> Vec<Self::Instr> {
+		let mut map = self.clone();
+		let mut txn = Vec::new();
+		delete_key(&mut map, key, &mut txn);
+		txn.push(map.map_b(map.1.delete(key.clone())));
+		txn
+	}
+	fn map_at(&self, key: K, instr: V::Instr) -> Self::Instr {
+		self.map_b(self.1.map_at(key, instr))
+	}
+	fn keys(&self) -> Vec<&K> {
+		let listed: BTreeSet<_> = (0..self.0.len()).map(|at| &self.0[at]).collect();
+		let mut unlisted: Vec<_> = self.1.keys().filter(|key| !listed.contains(key)).collect();
+		unlisted.sort_unstable();
+		first_occurrences(self).into_iter().map(|at| &self.0[at]).chain(unlisted).collect()
+	}
+	fn get(&self, key: &K) -> Option<&V> {
+		self.1.get(key)
+	}
+}
+
+/// Where in the list each key with a value first appears, in order
+fn first_occurrences<K, V>(map: &OrderedMap<K, V>) -> Vec<usize>
+where
+	K: State + Ord,
+	V: State,
+{
+	let mut seen = BTreeSet::new();
+	(0..map.0.len()).filter(|&at| map.1.get(&map.0[at]).is_some() && seen.insert(&map.0[at])).collect()
+}
+
+fn move_to<K, V>(map: &mut OrderedMap<K, V>, key: &K, to: usize, txn: &mut Vec<<OrderedMap<K, V> as State>::Instr>)
+where
+	K: State + Clone + Ord,
+	V: State + Clone,
+{
+	delete_key(map, key, txn);
+	let positions = first_occurrences(map);
+	assert!(to <= positions.len());
+	let at = positions.get(to).copied().unwrap_or(map.0.len());
+	let instr = map.map_a(map.0.insert(at, key.clone()));
+	map.apply(&instr);
+	txn.push(instr);
+}
+
+/// Deletes every occurrence of `key` from the list, leaving its value alone
+fn delete_key<K, V>(map: &mut OrderedMap<K, V>, key: &K, txn: &mut Vec<<OrderedMap<K, V> as State>::Instr>)
+where
+	K: State + Clone + Ord,
+	V: State + Clone,
+{
+	let occurrences: Vec<_> = (0..map.0.len()).filter(|&at| map.0[at] == *key).collect();
+	for at in occurrences.into_iter().rev() {
+		let instr = map.map_a(map.0.delete(at));
+		map.apply(&instr);
+		txn.push(instr);
+	}
+}
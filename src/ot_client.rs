@@ -1,57 +1,261 @@
-use std::collections::VecDeque;
+use std::{
+	collections::{HashMap, VecDeque}, slice, time::Duration
+};
 
-use otto::{State, StateTest};
+use otto::{
+	crdt::{Crdt, CrdtInstr}, State, StateTest
+};
 use rand::Rng;
 
-use crate::channel::{Receiver, Sender};
+use crate::{
+	channel::{Receiver, Sender}, gen_txn, ot_server::{ToClient, ToServer}, undo::UndoManager
+};
 
 #[derive(Debug)]
 pub struct OtClient<T>
 where
 	T: State,
 {
-	state: T,
+	document: Document<T>,
 	pending: VecDeque<T::Instr>,
-	from_server: Receiver<Option<T::Instr>>,
-	to_server: Sender<Option<T::Instr>>,
+	// the length of each of our transactions in `pending`
+	pending_txns: VecDeque<usize>,
+	from_server: Receiver<ToClient<T::Instr>>,
+	to_server: Sender<ToServer<T::Instr>>,
+	cursor: Option<T::Instr>,
+	// other clients' cursors, by their index on the server
+	cursors: HashMap<usize, T::Instr>,
+	rejections: Vec<String>,
+}
+
+#[derive(Debug)]
+enum Document<T>
+where
+	T: State,
+{
+	// `acked` is `state` without `pending`, for `pending` to be reapplied onto when the server rejects one of our transactions
+	Plain { state: T, acked: T },
+	// the whole history is kept, so that inverses of our own acknowledged instructions can be computed, rebased over everything applied
+	// since. `pending_txns` holds each of our transactions in `pending` as applied to `crdt`, to revert it if the server rejects it
+	Undo { crdt: Crdt<T>, undo_manager: UndoManager<T>, pending_txns: VecDeque<Vec<CrdtInstr<T>>> },
 }
 
 impl<T> OtClient<T>
 where
 	T: StateTest,
 {
-	pub fn new(state: T, from_server: Receiver<Option<T::Instr>>, to_server: Sender<Option<T::Instr>>) -> Self {
-		Self { state, pending: VecDeque::new(), from_server, to_server }
+	pub fn new(state: T, from_server: Receiver<ToClient<T::Instr>>, to_server: Sender<ToServer<T::Instr>>) -> Self {
+		Self {
+			document: Document::Plain { acked: state.clone(), state },
+			pending: VecDeque::new(),
+			pending_txns: VecDeque::new(),
+			from_server,
+			to_server,
+			cursor: None,
+			cursors: HashMap::new(),
+			rejections: Vec::new(),
+		}
+	}
+	/// Has to be called before any edits
+	pub fn with_undo(self) -> Self {
+		self.with_undo_manager(UndoManager::default())
+	}
+	pub fn with_capture_timeout(self, capture_timeout: Duration) -> Self {
+		self.with_undo_manager(UndoManager::new(capture_timeout))
 	}
 	pub fn gen_and_send(&mut self, rng: &mut impl Rng) {
-		let instr = StateTest::gen_trivial_instr(&self.state, rng).unwrap();
-		self.state.apply(&instr);
-		let instr_clone = instr.clone();
-		let instr = T::insert_and_rebase_back(instr, &*self.pending.make_contiguous());
-		self.pending.push_back(instr_clone);
-		self.to_server.send(Some(instr));
+		let txn = gen_txn(self.state(), rng);
+		self.apply_and_send(txn);
+	}
+	pub fn apply_and_send(&mut self, txn: Vec<T::Instr>) {
+		for instr in &txn {
+			self.transform_cursors(instr);
+		}
+		match &mut self.document {
+			Document::Plain { state, .. } => txn.iter().for_each(|instr| state.apply(instr)),
+			Document::Undo { crdt, undo_manager, pending_txns } => {
+				let crdt_txn: Vec<_> = txn
+					.iter()
+					.map(|instr| {
+						let crdt_instr = crdt.instr_to_crdt_instr(instr.clone());
+						crdt.apply(crdt_instr.clone());
+						crdt_instr
+					})
+					.collect();
+				undo_manager.record_unit(crdt_txn.clone());
+				pending_txns.push_back(crdt_txn);
+			}
+		}
+		self.send(txn);
+	}
+	/// Does nothing while the server hasn't acknowledged the unit, as it could still be rejected
+	pub fn undo_and_send(&mut self) -> bool {
+		let Document::Undo { undo_manager, pending_txns, .. } = &mut self.document else { return false };
+		if undo_manager.next_undo().map_or(false, |unit| is_pending(pending_txns, unit)) {
+			return false;
+		}
+		let Some(crdt_txn) = undo_manager.undo() else { return false };
+		self.send_crdt_txn(crdt_txn);
+		true
+	}
+	pub fn stop_capturing(&mut self) {
+		if let Document::Undo { undo_manager, .. } = &mut self.document {
+			undo_manager.stop_capturing();
+		}
+	}
+	pub fn redo_and_send(&mut self) -> bool {
+		let Document::Undo { undo_manager, pending_txns, .. } = &mut self.document else { return false };
+		if undo_manager.next_redo().map_or(false, |unit| is_pending(pending_txns, unit)) {
+			return false;
+		}
+		let Some(crdt_txn) = undo_manager.redo() else { return false };
+		self.send_crdt_txn(crdt_txn);
+		true
+	}
+	pub fn set_cursor_and_send(&mut self, cursor: Option<T::Instr>) {
+		self.cursor = cursor.clone();
+		let cursor = cursor.map(|cursor| T::insert_and_rebase_back(cursor, &*self.pending.make_contiguous()));
+		self.to_server.send(ToServer::Presence(cursor));
 	}
 	pub fn try_recv_and_commit(&mut self) -> bool {
-		if let Some(instr) = self.from_server.try_receive() {
-			match instr {
-				Some(instr) => {
-					let instr = T::converge(instr, self.pending.make_contiguous());
-					self.state.apply(&instr);
+		if let Some(msg) = self.from_server.try_receive() {
+			match msg {
+				ToClient::Txn(txn) => {
+					for instr in txn {
+						if let Document::Plain { acked, .. } = &mut self.document {
+							acked.apply(&instr);
+						}
+						let instr = T::converge(instr, self.pending.make_contiguous());
+						self.transform_cursors(&instr);
+						match &mut self.document {
+							Document::Plain { state, .. } => state.apply(&instr),
+							Document::Undo { crdt, .. } => crdt.apply_(instr),
+						}
+					}
 				}
-				None => {
-					let _instr = self.pending.pop_front().unwrap();
+				ToClient::Ack => {
+					let instrs = self.pending.drain(..self.pending_txns.pop_front().unwrap());
+					match &mut self.document {
+						Document::Plain { acked, .. } => instrs.for_each(|instr| acked.apply(&instr)),
+						Document::Undo { pending_txns, .. } => {
+							let _crdt_txn = pending_txns.pop_front().unwrap();
+						}
+					}
+				}
+				ToClient::Presence(client, Some(cursor)) => {
+					let cursor = T::insert_and_rebase_forward(cursor, &*self.pending.make_contiguous());
+					let _ = self.cursors.insert(client, cursor);
+				}
+				ToClient::Presence(client, None) => {
+					let _ = self.cursors.remove(&client);
+				}
+				ToClient::Reject(reason) => {
+					self.revert_oldest_txn();
+					self.rejections.push(reason);
 				}
 			}
-			self.to_server.send(None);
+			self.to_server.send(ToServer::Ack);
 			true
 		} else {
 			false
 		}
 	}
 	pub fn state(&self) -> &T {
-		&self.state
+		match &self.document {
+			Document::Plain { state, .. } => state,
+			Document::Undo { crdt, .. } => crdt,
+		}
+	}
+	pub fn cursor(&self) -> Option<&T::Instr> {
+		self.cursor.as_ref()
+	}
+	pub fn cursors(&self) -> &HashMap<usize, T::Instr> {
+		&self.cursors
+	}
+	pub fn rejections(&self) -> &[String] {
+		&self.rejections
 	}
 	pub fn drained(&self) -> bool {
 		self.pending.is_empty() && self.from_server.is_empty() && self.to_server.is_empty()
 	}
+	fn with_undo_manager(mut self, undo_manager: UndoManager<T>) -> Self {
+		assert!(self.pending.is_empty());
+		let Document::Plain { state, .. } = self.document else { panic!("undo is already enabled") };
+		self.document = Document::Undo { crdt: Crdt::new(state), undo_manager, pending_txns: VecDeque::new() };
+		self
+	}
+	fn send_crdt_txn(&mut self, crdt_txn: Vec<CrdtInstr<T>>) {
+		let Document::Undo { crdt, pending_txns, .. } = &mut self.document else { unreachable!() };
+		let txn: Vec<_> = crdt_txn
+			.iter()
+			.map(|crdt_instr| {
+				let instr = crdt.instr_from_crdt_instr_(crdt_instr.clone());
+				crdt.apply(crdt_instr.clone());
+				instr
+			})
+			.collect();
+		pending_txns.push_back(crdt_txn);
+		for instr in &txn {
+			self.transform_cursors(instr);
+		}
+		self.send(txn);
+	}
+	fn send(&mut self, txn: Vec<T::Instr>) {
+		let txn: Vec<_> = txn
+			.into_iter()
+			.map(|instr| {
+				let instr_clone = instr.clone();
+				let instr = T::insert_and_rebase_back(instr, &*self.pending.make_contiguous());
+				self.pending.push_back(instr_clone);
+				instr
+			})
+			.collect();
+		self.pending_txns.push_back(txn.len());
+		self.to_server.send(ToServer::Txn(txn));
+	}
+	// everything after the reverted transaction is rebased back over it, taking it out of `pending`
+	fn revert_oldest_txn(&mut self) {
+		let len = self.pending_txns.pop_front().unwrap();
+		let pending: Vec<_> = self.pending.drain(..).collect();
+		for (i, instr) in pending.iter().enumerate().skip(len) {
+			let instr = T::insert_and_rebase_back(instr.clone(), &pending[..i]);
+			let instr = T::insert_and_rebase_forward(instr, &*self.pending.make_contiguous());
+			self.pending.push_back(instr);
+		}
+		match &mut self.document {
+			Document::Plain { state, acked } => {
+				*state = acked.clone();
+				self.pending.iter().for_each(|instr| state.apply(instr));
+				// cursors are rebased back onto `acked`, and forward onto `state` again
+				let pending_ = &*self.pending.make_contiguous();
+				let rebase = |cursor| T::insert_and_rebase_forward(T::insert_and_rebase_back(cursor, &pending[..]), pending_);
+				self.cursor = self.cursor.take().map(rebase);
+				self.cursors.values_mut().for_each(|cursor| *cursor = rebase(cursor.clone()));
+			}
+			Document::Undo { crdt, undo_manager, pending_txns } => {
+				let crdt_txn = pending_txns.pop_front().unwrap();
+				undo_manager.forget(&crdt_txn);
+				let instrs: Vec<_> = crdt_txn
+					.into_iter()
+					.rev()
+					.map(|crdt_instr| {
+						let crdt_instr = crdt_instr.inverse();
+						let instr = crdt.instr_from_crdt_instr_(crdt_instr.clone());
+						crdt.apply(crdt_instr);
+						instr
+					})
+					.collect();
+				instrs.iter().for_each(|instr| self.transform_cursors(instr));
+			}
+		}
+	}
+	fn transform_cursors(&mut self, instr: &T::Instr) {
+		let instrs = slice::from_ref(instr);
+		self.cursor = self.cursor.take().map(|cursor| T::insert_and_rebase_forward(cursor, instrs));
+		self.cursors.values_mut().for_each(|cursor| *cursor = T::insert_and_rebase_forward(cursor.clone(), instrs));
+	}
+}
+
+fn is_pending<T: State>(pending_txns: &VecDeque<Vec<CrdtInstr<T>>>, crdt_instrs: &[CrdtInstr<T>]) -> bool {
+	pending_txns.iter().flatten().any(|crdt_instr| crdt_instrs.contains(crdt_instr))
 }
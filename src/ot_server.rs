@@ -3,7 +3,27 @@ use std::collections::VecDeque;
 use otto::State;
 use rand::{seq::IteratorRandom, Rng};
 
-use crate::channel::{Receiver, Sender};
+use crate::{
+	channel::{Receiver, Sender}, validation::{self, Authorizer, Validator}
+};
+
+#[derive(Clone, Debug)]
+pub enum ToClient<I> {
+	Txn(Vec<I>),
+	Ack,
+	/// Another client's cursor, or `None` once that client has cleared it or disconnected
+	Presence(usize, Option<I>),
+	/// Refuses the client's oldest unacknowledged transaction
+	Reject(String),
+}
+
+#[derive(Clone, Debug)]
+pub enum ToServer<I> {
+	/// Rebased back over the client's unacknowledged transactions
+	Txn(Vec<I>),
+	Ack,
+	Presence(Option<I>),
+}
 
 #[derive(Debug)]
 pub struct OtServer<T>
@@ -12,6 +32,10 @@ where
 {
 	pub(crate) pending: VecDeque<T::Instr>,
 	pub(crate) clients: Vec<OtServerClient<T>>,
+	// the document as of the end of `pending`, which client transactions are checked against
+	document: Option<T>,
+	validator: Option<Validator<T>>,
+	authorizer: Option<Authorizer<T>>,
 }
 
 #[derive(Debug)]
@@ -20,53 +44,116 @@ where
 	T: State,
 {
 	pub(crate) offset: usize,
-	pub(crate) to_client: Sender<Option<T::Instr>>,
-	pub(crate) from_client: Receiver<Option<T::Instr>>,
+	// number of instructions covered by each message sent to the client that it hasn't acknowledged yet
+	pub(crate) in_flight: VecDeque<usize>,
+	pub(crate) to_client: Sender<ToClient<T::Instr>>,
+	pub(crate) from_client: Receiver<ToServer<T::Instr>>,
 }
 
 impl<T> OtServer<T>
 where
 	T: State,
 {
-	pub fn new(channels: impl Iterator<Item = (Sender<Option<T::Instr>>, Receiver<Option<T::Instr>>)>) -> Self {
-		Self { pending: VecDeque::new(), clients: channels.map(|(to_client, from_client)| OtServerClient::new(to_client, from_client)).collect() }
+	pub fn new(channels: impl Iterator<Item = (Sender<ToClient<T::Instr>>, Receiver<ToServer<T::Instr>>)>) -> Self {
+		Self {
+			pending: VecDeque::new(),
+			clients: channels.map(|(to_client, from_client)| OtServerClient::new(to_client, from_client)).collect(),
+			document: None,
+			validator: None,
+			authorizer: None,
+		}
+	}
+	/// Has to be called before any client transactions arrive
+	pub fn with_document(mut self, state: T) -> Self {
+		assert!(self.pending.is_empty());
+		self.document = Some(state);
+		self
+	}
+	pub fn with_validation(mut self, validator: Validator<T>) -> Self {
+		self.validator = Some(validator);
+		self
+	}
+	pub fn with_authorization(mut self, authorizer: Authorizer<T>) -> Self {
+		self.authorizer = Some(authorizer);
+		self
 	}
 	pub fn try_recv_and_send(&mut self, rng: &mut impl Rng) -> bool {
 		if let Some((client, OtServerClient { from_client, .. })) =
 			self.clients.iter_mut().enumerate().filter(|(_, OtServerClient { from_client, .. })| !from_client.is_empty()).choose(rng)
 		{
 			match from_client.try_receive().unwrap() {
-				Some(instr) => {
-					let offset = self.clients[client].offset;
-					let instr = T::insert_and_rebase_forward(instr, &self.pending.make_contiguous()[offset..]);
-					self.pending.push_back(instr.clone());
-					self.clients.iter_mut().enumerate().for_each(|(client_, OtServerClient { to_client, .. })| {
-						to_client.send(if client_ != client { Some(instr.clone()) } else { None });
-					});
-				}
-				None => {
-					self.clients[client].offset += 1;
-					let x = self.clients.iter().map(|&OtServerClient { offset, .. }| offset).min().unwrap();
-					for _ in 0..x {
-						let _ = self.pending.pop_front().unwrap();
+				ToServer::Txn(txn) => {
+					let txn = self.rebase_forward(client, txn);
+					if let Err(reason) = self.check(client, &txn) {
+						self.pending.truncate(self.pending.len() - txn.len());
+						self.clients[client].send(ToClient::Reject(reason), 0);
+						return true;
 					}
-					self.clients.iter_mut().for_each(|OtServerClient { offset, .. }| *offset -= x);
+					self.clients.iter_mut().enumerate().for_each(|(client_, ot_server_client)| {
+						ot_server_client.send(if client_ != client { ToClient::Txn(txn.clone()) } else { ToClient::Ack }, txn.len());
+					});
 				}
+				ToServer::Ack => self.ack(client),
+				ToServer::Presence(cursor) => self.relay_presence(client, cursor),
 			}
 			true
 		} else {
 			false
 		}
 	}
+	/// Channels have no notion of disconnecting, so this has to be called by whoever notices
+	pub fn expire_presence(&mut self, client: usize) {
+		self.relay_presence(client, None);
+	}
 	pub fn drained(&self) -> bool {
 		self.pending.is_empty()
 	}
+	pub(crate) fn rebase_forward(&mut self, client: usize, txn: Vec<T::Instr>) -> Vec<T::Instr> {
+		let offset = self.clients[client].offset;
+		txn.into_iter()
+			.map(|instr| {
+				let instr = T::insert_and_rebase_forward(instr, &self.pending.make_contiguous()[offset..]);
+				self.pending.push_back(instr.clone());
+				instr
+			})
+			.collect()
+	}
+	fn check(&mut self, client: usize, txn: &[T::Instr]) -> Result<(), String> {
+		if self.validator.is_none() && self.authorizer.is_none() {
+			return Ok(());
+		}
+		let state = self.document.as_mut().expect("validation and authorization need the document, see `OtServer::with_document`");
+		*state = validation::check(state, client, txn, self.authorizer.as_ref(), self.validator.as_ref())?;
+		Ok(())
+	}
+	pub(crate) fn relay_presence(&mut self, client: usize, cursor: Option<T::Instr>) {
+		let cursor = cursor.map(|cursor| self.rebase_cursor(client, cursor));
+		self.clients.iter_mut().enumerate().filter(|&(client_, _)| client_ != client).for_each(|(_, ot_server_client)| {
+			ot_server_client.send(ToClient::Presence(client, cursor.clone()), 0);
+		});
+	}
+	pub(crate) fn rebase_cursor(&mut self, client: usize, cursor: T::Instr) -> T::Instr {
+		let offset = self.clients[client].offset;
+		T::insert_and_rebase_forward(cursor, &self.pending.make_contiguous()[offset..])
+	}
+	pub(crate) fn ack(&mut self, client: usize) {
+		self.clients[client].offset += self.clients[client].in_flight.pop_front().unwrap();
+		let x = self.clients.iter().map(|&OtServerClient { offset, .. }| offset).min().unwrap();
+		for _ in 0..x {
+			let _ = self.pending.pop_front().unwrap();
+		}
+		self.clients.iter_mut().for_each(|OtServerClient { offset, .. }| *offset -= x);
+	}
 }
 impl<T> OtServerClient<T>
 where
 	T: State,
 {
-	fn new(to_client: Sender<Option<T::Instr>>, from_client: Receiver<Option<T::Instr>>) -> Self {
-		Self { offset: 0, to_client, from_client }
+	fn new(to_client: Sender<ToClient<T::Instr>>, from_client: Receiver<ToServer<T::Instr>>) -> Self {
+		Self { offset: 0, in_flight: VecDeque::new(), to_client, from_client }
+	}
+	pub(crate) fn send(&mut self, msg: ToClient<T::Instr>, len: usize) {
+		self.in_flight.push_back(len);
+		self.to_client.send(msg);
 	}
 }
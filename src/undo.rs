@@ -0,0 +1,105 @@
+//! Per-user undo and redo on top of [`CrdtInstr::inverse`].
+
+use std::time::{Duration, Instant};
+
+use otto::{crdt::CrdtInstr, State};
+
+/// Undo and redo stacks for the edits of a single user. Only edits passed to [`UndoManager::record`] are undone, so other users'
+/// edits applied to the same `Crdt` in the meantime are left alone
+#[derive(Debug)]
+pub struct UndoManager<T>
+where
+	T: State,
+{
+	undos: Vec<Vec<CrdtInstr<T>>>,
+	redos: Vec<Vec<CrdtInstr<T>>>,
+	capture_timeout: Duration,
+	last_edit: Option<Instant>,
+}
+
+impl<T> UndoManager<T>
+where
+	T: State,
+{
+	/// Edits made within `capture_timeout` of each other are grouped into one undo unit
+	pub fn new(capture_timeout: Duration) -> Self {
+		Self { undos: Vec::new(), redos: Vec::new(), capture_timeout, last_edit: None }
+	}
+	/// Records a local edit, joining the current undo unit if it was made within the capture timeout of the previous one
+	pub fn record(&mut self, instr: CrdtInstr<T>) {
+		self.record_unit(vec![instr]);
+	}
+	/// Records several local edits, e.g. a transaction, together, as a new undo unit unless made within the capture timeout of the
+	/// previous edit
+	pub fn record_unit(&mut self, instrs: Vec<CrdtInstr<T>>) {
+		if instrs.is_empty() {
+			return;
+		}
+		let now = Instant::now();
+		match (self.last_edit, self.undos.last_mut()) {
+			(Some(last_edit), Some(unit)) if now.duration_since(last_edit) < self.capture_timeout => unit.extend(instrs),
+			_ => self.undos.push(instrs),
+		}
+		self.last_edit = Some(now);
+		self.redos.clear();
+	}
+	/// Closes the current undo unit, so that the next edit starts a new one
+	pub fn stop_capturing(&mut self) {
+		self.last_edit = None;
+	}
+	/// Instructions undoing the most recent undo unit, to be applied and sent in order
+	pub fn undo(&mut self) -> Option<Vec<CrdtInstr<T>>> {
+		let inverses = Self::invert(self.undos.pop()?);
+		self.redos.push(inverses.clone());
+		self.last_edit = None;
+		Some(inverses)
+	}
+	/// Instructions redoing the most recently undone unit, to be applied and sent in order
+	pub fn redo(&mut self) -> Option<Vec<CrdtInstr<T>>> {
+		let inverses = Self::invert(self.redos.pop()?);
+		self.undos.push(inverses.clone());
+		self.last_edit = None;
+		Some(inverses)
+	}
+	/// The unit [`UndoManager::undo`] would undo next
+	pub fn next_undo(&self) -> Option<&[CrdtInstr<T>]> {
+		self.undos.last().map(Vec::as_slice)
+	}
+	/// The unit [`UndoManager::redo`] would redo next
+	pub fn next_redo(&self) -> Option<&[CrdtInstr<T>]> {
+		self.redos.last().map(Vec::as_slice)
+	}
+	pub fn can_undo(&self) -> bool {
+		!self.undos.is_empty()
+	}
+	pub fn can_redo(&self) -> bool {
+		!self.redos.is_empty()
+	}
+	/// Forgets local edits that were reverted rather than undone, e.g. because the server rejected them, so that undo and redo skip them
+	pub fn forget(&mut self, instrs: &[CrdtInstr<T>]) {
+		for stack in [&mut self.undos, &mut self.redos] {
+			stack.iter_mut().for_each(|unit| unit.retain(|instr| !instrs.contains(instr)));
+			stack.retain(|unit| !unit.is_empty());
+		}
+		self.last_edit = None;
+	}
+	/// Forgets all undo and redo history
+	pub fn clear(&mut self) {
+		self.undos.clear();
+		self.redos.clear();
+		self.last_edit = None;
+	}
+	fn invert(unit: Vec<CrdtInstr<T>>) -> Vec<CrdtInstr<T>> {
+		unit.into_iter().rev().map(|instr| instr.inverse()).collect()
+	}
+}
+
+impl<T> Default for UndoManager<T>
+where
+	T: State,
+{
+	/// Every edit, or transaction passed to [`UndoManager::record_unit`], is its own undo unit
+	fn default() -> Self {
+		Self::new(Duration::ZERO)
+	}
+}
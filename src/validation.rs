@@ -0,0 +1,79 @@
+//! Hooks for [`OtServer`](crate::ot_server::OtServer) to refuse client transactions.
+
+use std::fmt;
+
+use otto::{list::List, State};
+
+/// An invariant of the document, explaining why it doesn't hold otherwise
+pub struct Validator<T>(Box<dyn Fn(&T) -> Result<(), String>>);
+
+impl<T> Validator<T> {
+	pub fn new(validate: impl Fn(&T) -> Result<(), String> + 'static) -> Self {
+		Self(Box::new(validate))
+	}
+	pub fn validate(&self, state: &T) -> Result<(), String> {
+		(self.0)(state)
+	}
+}
+
+impl<T> Validator<List<T>>
+where
+	T: State,
+{
+	pub fn max_len(max: usize) -> Self {
+		Self::new(move |list| if list.len() <= max { Ok(()) } else { Err(format!("{} elements, at most {max} allowed", list.len())) })
+	}
+}
+
+impl<T> fmt::Debug for Validator<T> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Validator").finish_non_exhaustive()
+	}
+}
+
+/// Decides whether a client, identified by its index on the server, may apply an instruction to the document, explaining why not
+/// otherwise. otto doesn't expose the contents of instructions, so what an instruction touches, e.g. a field of a derived struct or a
+/// `Map` key, is told apart by comparing the document before and after applying it
+pub struct Authorizer<T: State>(Box<dyn Fn(usize, &T, &T::Instr) -> Result<(), String>>);
+
+impl<T> Authorizer<T>
+where
+	T: State,
+{
+	pub fn new(authorize: impl Fn(usize, &T, &T::Instr) -> Result<(), String> + 'static) -> Self {
+		Self(Box::new(authorize))
+	}
+	pub fn authorize(&self, client: usize, state: &T, instr: &T::Instr) -> Result<(), String> {
+		(self.0)(client, state, instr)
+	}
+}
+
+impl<T> fmt::Debug for Authorizer<T>
+where
+	T: State,
+{
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Authorizer").finish_non_exhaustive()
+	}
+}
+
+/// Applies a transaction from `client` to a copy of `state` if `authorizer` lets the client make each of its instructions and the result
+/// upholds `validator`'s invariant, and explains why not otherwise
+pub(crate) fn check<T>(
+	state: &T, client: usize, txn: &[T::Instr], authorizer: Option<&Authorizer<T>>, validator: Option<&Validator<T>>,
+) -> Result<T, String>
+where
+	T: State,
+{
+	let mut state = state.clone();
+	for instr in txn {
+		if let Some(authorizer) = authorizer {
+			authorizer.authorize(client, &state, instr)?;
+		}
+		state.apply(instr);
+	}
+	if let Some(validator) = validator {
+		validator.validate(&state)?;
+	}
+	Ok(state)
+}
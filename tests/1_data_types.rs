@@ -1,6 +1,9 @@
+use itertools::Itertools;
 use otto::{crdt::Crdt, list::List, map::Map, mappable_register::MappableRegister, set::Set, State, StateTest, text::Text};
 use rand::{Rng, rngs::SmallRng, SeedableRng};
 
+use otto_test::ordered_map::{OrderedMap, OttoOrderedMap};
+
 #[ignore]
 #[test]
 fn fuzz_register() {
@@ -89,6 +92,77 @@ fn fuzz_short_map() {
 	fuzz_short::<Map<u8, Text>>();
 }
 
+#[test]
+fn fuzz_short_ordered_map() {
+	let seed = rand::random();
+	println!("seed: {seed}");
+	let rng = &mut SmallRng::seed_from_u64(seed);
+	for _ in 0..100 {
+		let mut a = Crdt::new(<OrderedMap<u8, Text>>::gen(rng));
+		let mut b = a.clone();
+		for crdt in [&mut a, &mut b] {
+			for _ in 0..rng.gen_range(1..5) {
+				let txn = gen_ordered_map_txn(crdt, rng);
+				apply_txn(crdt, txn);
+			}
+		}
+		merge(&mut a, &mut b);
+		assert_eq!(a, b);
+		assert!(a.keys().into_iter().all_unique());
+	}
+}
+
+#[test]
+fn ordered_map_reorder_keeps_concurrent_value_edits() {
+	let rng = &mut SmallRng::seed_from_u64(0);
+	let mut a = <Crdt<OrderedMap<u8, Text>>>::new((List::new(), Map::new()));
+	for (at, key) in [1, 2].into_iter().enumerate() {
+		let txn = a.insert_at(at, key, Text::gen(rng));
+		apply_txn(&mut a, txn);
+	}
+	let mut b = a.clone();
+
+	let txn = a.reorder(&1, 1);
+	apply_txn(&mut a, txn);
+	let edit = StateTest::gen_trivial_instr(b.get(&1).unwrap(), rng).unwrap();
+	let mut value = b.get(&1).unwrap().clone();
+	value.apply(&edit);
+	let instr = b.map_at(1, edit);
+	apply_txn(&mut b, vec![instr]);
+	merge(&mut a, &mut b);
+	assert_eq!(a, b);
+	assert_eq!(a.keys(), [&2, &1]);
+	assert_eq!(a.get(&1), Some(&value));
+}
+
+fn gen_ordered_map_txn(map: &OrderedMap<u8, Text>, rng: &mut impl Rng) -> Vec<<OrderedMap<u8, Text> as State>::Instr> {
+	let keys = map.keys();
+	if keys.is_empty() || rng.gen_range(0..4) == 0 {
+		return map.insert_at(rng.gen_range(0..=keys.len()), rng.gen(), Text::gen(rng));
+	}
+	let key = *keys[rng.gen_range(0..keys.len())];
+	match rng.gen_range(0..3) {
+		0 => map.reorder(&key, rng.gen_range(0..keys.len())),
+		1 => map.delete(&key),
+		_ => vec![map.map_at(key, StateTest::gen_trivial_instr(map.get(&key).unwrap(), rng).unwrap())],
+	}
+}
+
+fn apply_txn<T: State>(crdt: &mut Crdt<T>, txn: Vec<T::Instr>) {
+	for instr in txn {
+		let instr = crdt.instr_to_crdt_instr(instr);
+		crdt.apply(instr);
+	}
+}
+
+/// Exchanges the whole history of two replicas that started out the same but haven't exchanged anything since
+fn merge<T: State>(a: &mut Crdt<T>, b: &mut Crdt<T>) {
+	let a_instrs: Vec<_> = a.instrs().collect();
+	let b_instrs: Vec<_> = b.instrs().collect();
+	b.apply_multiple(a_instrs);
+	a.apply_multiple(b_instrs);
+}
+
 #[derive(Clone, PartialEq, Eq, State, StateTest, Debug)]
 struct FooStruct {
 	a: Text,
@@ -1,11 +1,16 @@
 #![allow(clippy::if_not_else, clippy::range_plus_one)]
 
+use std::{iter, time::Duration};
+
 use itertools::Itertools;
-use otto::{list::List, mappable_register::MappableRegister, StateTest};
+use otto::{crdt::Crdt, list::{List, OttoList}, mappable_register::MappableRegister, StateTest};
 use rand::{prelude::SliceRandom, Rng, rngs::SmallRng, SeedableRng};
 use random_branch::branch_using;
 
-use otto_test::{channel::channel, crdt_client::CrdtClient};
+mod helpers;
+
+use helpers::contents;
+use otto_test::{channel::channel, crdt_client::CrdtClient, undo::UndoManager};
 
 fn test_crdt<T: StateTest>(rng: &mut impl Rng) {
 	let clients = 5;
@@ -39,6 +44,24 @@ fn test_crdt<T: StateTest>(rng: &mut impl Rng) {
 						continue;
 					}
 				},
+				{
+					if iters == 0 {
+						continue;
+					}
+					let client = clients.choose_mut(rng).unwrap();
+					if !client.undo_and_send() {
+						continue;
+					}
+				},
+				{
+					if iters == 0 {
+						continue;
+					}
+					let client = clients.choose_mut(rng).unwrap();
+					if !client.redo_and_send() {
+						continue;
+					}
+				},
 			});
 		}
 		iters = iters.saturating_sub(1);
@@ -69,3 +92,61 @@ fn fuzz_crdt_short() {
 		test_crdt::<List<List<MappableRegister<u64>>>>(rng);
 	}
 }
+
+#[test]
+fn undo_skips_other_users_edits() {
+	let mut a = Crdt::new(<List<u8>>::new());
+	let mut b = a.clone();
+	let mut undo_manager = UndoManager::default();
+
+	let instr = a.instr_to_crdt_instr(a.insert(0, 1));
+	undo_manager.record(instr.clone());
+	a.apply(instr.clone());
+	b.apply(instr);
+
+	let instr = b.instr_to_crdt_instr(b.insert(1, 2));
+	b.apply(instr.clone());
+	a.apply(instr);
+
+	for instr in undo_manager.undo().unwrap() {
+		a.apply(instr.clone());
+		b.apply(instr);
+	}
+	assert_eq!(a, b);
+	assert_eq!(contents(&a), [2]);
+	assert!(undo_manager.undo().is_none());
+
+	for instr in undo_manager.redo().unwrap() {
+		a.apply(instr.clone());
+		b.apply(instr);
+	}
+	assert_eq!(a, b);
+	assert_eq!(contents(&a), [1, 2]);
+	assert!(undo_manager.redo().is_none());
+}
+
+#[test]
+fn undo_groups_edits_by_time() {
+	let (to_a, inbox_a) = channel();
+	let (to_b, inbox_b) = channel();
+	let mut a = CrdtClient::new(<List<u8>>::new(), inbox_a, iter::once(to_b)).with_capture_timeout(Duration::from_secs(60 * 60));
+	let mut b = CrdtClient::new(<List<u8>>::new(), inbox_b, iter::once(to_a));
+
+	for (at, x) in [(0, 1), (1, 2)] {
+		let instr = a.state().insert(at, x);
+		a.apply_and_send(vec![instr]);
+	}
+	a.stop_capturing();
+	let instr = a.state().insert(2, 3);
+	a.apply_and_send(vec![instr]);
+	assert_eq!(contents(a.state()), [1, 2, 3]);
+
+	assert!(a.undo_and_send());
+	assert_eq!(contents(a.state()), [1, 2]);
+	assert!(a.undo_and_send());
+	assert!(contents(a.state()).is_empty());
+	assert!(!a.undo_and_send());
+
+	while b.try_recv_and_commit() {}
+	assert!(contents(b.state()).is_empty());
+}
@@ -1,21 +1,44 @@
 #![allow(clippy::if_not_else, clippy::range_plus_one)]
 
 use itertools::{Itertools, multizip as zip};
-use otto::{list::List, mappable_register::MappableRegister, StateTest, text::Text};
+use otto::{list::{List, OttoList}, mappable_register::MappableRegister, State, StateTest, text::Text};
 use rand::{prelude::SliceRandom, Rng, rngs::SmallRng, SeedableRng};
 use random_branch::branch_using;
 
-use otto_test::{channel::channel, ot_client::OtClient, ot_server::OtServer};
+mod helpers;
 
-fn test_ot<T: StateTest>(rng: &mut impl Rng) {
+use helpers::{contents, insert_txn, list_session, settle};
+use otto_test::{
+	channel::channel, ot_client::OtClient, ot_server::{OtServer, ToClient, ToServer}, validation::{Authorizer, Validator}
+};
+
+fn test_ot<T: StateTest>(rng: &mut impl Rng, validator: Option<Validator<T>>, authorizer: Option<Authorizer<T>>) {
 	let clients = 5;
 	let mut iters = 100usize;
-	let start = T::gen(rng);
-	let (to_client, from_server): (Vec<_>, Vec<_>) = (0..clients).map(|_| channel::<Option<T::Instr>>()).multiunzip();
-	let (to_server, from_client): (Vec<_>, Vec<_>) = (0..clients).map(|_| channel::<Option<T::Instr>>()).multiunzip();
-	let mut clients =
-		zip((from_server, to_server)).map(|(from_server, to_server)| OtClient::new(start.clone(), from_server, to_server)).collect::<Vec<_>>();
-	let mut server = OtServer::<T>::new(zip((to_client, from_client)));
+	// every transaction would be rejected if the document started out breaking the invariant
+	let start = loop {
+		let start = T::gen(rng);
+		if validator.as_ref().map_or(true, |validator| validator.validate(&start).is_ok()) {
+			break start;
+		}
+	};
+	let (to_client, from_server): (Vec<_>, Vec<_>) = (0..clients).map(|_| channel::<ToClient<T::Instr>>()).multiunzip();
+	let (to_server, from_client): (Vec<_>, Vec<_>) = (0..clients).map(|_| channel::<ToServer<T::Instr>>()).multiunzip();
+	// only some clients keep the history undo needs, so that rejections are reverted both with and without it
+	let mut clients = zip((from_server, to_server))
+		.enumerate()
+		.map(|(i, (from_server, to_server))| {
+			let client = OtClient::new(start.clone(), from_server, to_server);
+			if i % 2 == 0 { client.with_undo() } else { client }
+		})
+		.collect::<Vec<_>>();
+	let mut server = OtServer::<T>::new(zip((to_client, from_client))).with_document(start.clone());
+	if let Some(validator) = validator {
+		server = server.with_validation(validator);
+	}
+	if let Some(authorizer) = authorizer {
+		server = server.with_authorization(authorizer);
+	}
 	while iters != 0 || !clients.iter().all(OtClient::drained) {
 		loop {
 			break branch_using!(*rng, {
@@ -37,12 +60,53 @@ fn test_ot<T: StateTest>(rng: &mut impl Rng) {
 						continue;
 					}
 				},
+				{
+					if iters == 0 {
+						continue;
+					}
+					let client = clients.choose_mut(rng).unwrap();
+					let cursor = StateTest::gen_trivial_instr(client.state(), rng);
+					client.set_cursor_and_send(cursor);
+				},
+				{
+					if iters == 0 {
+						continue;
+					}
+					let client = clients.choose_mut(rng).unwrap();
+					if !client.undo_and_send() {
+						continue;
+					}
+				},
+				{
+					if iters == 0 {
+						continue;
+					}
+					let client = clients.choose_mut(rng).unwrap();
+					if !client.redo_and_send() {
+						continue;
+					}
+				},
 			});
 		}
 		iters = iters.saturating_sub(1);
 	}
 	assert!(clients.iter().map(OtClient::state).all_equal());
 	assert!(server.drained());
+	// cursors are compared by their effect, as differently rebased instructions can have the same one
+	for peer in 0..clients.len() {
+		assert!(clients
+			.iter()
+			.enumerate()
+			.map(|(client, ot_client)| {
+				let cursor = if client == peer { ot_client.cursor() } else { ot_client.cursors().get(&peer) };
+				cursor.map(|cursor| {
+					let mut state = ot_client.state().clone();
+					state.apply(cursor);
+					state
+				})
+			})
+			.all_equal());
+	}
 }
 
 #[ignore]
@@ -55,8 +119,10 @@ fn fuzz_ot() {
 		if i % 1_000 == 0 {
 			println!("{}", i);
 		}
-		test_ot::<Text>(rng);
-		test_ot::<List<List<MappableRegister<u64>>>>(rng);
+		test_ot::<Text>(rng, None, None);
+		test_ot::<List<List<MappableRegister<u64>>>>(rng, None, None);
+		test_ot::<List<u8>>(rng, Some(Validator::max_len(5)), None);
+		test_ot::<Text>(rng, None, Some(Authorizer::new(read_only_client_0)));
 	}
 }
 
@@ -66,7 +132,160 @@ fn fuzz_ot_short() {
 	println!("seed: {seed}");
 	let rng = &mut SmallRng::seed_from_u64(seed);
 	for _ in 0..100 {
-		test_ot::<Text>(rng);
-		test_ot::<List<List<MappableRegister<u64>>>>(rng);
+		test_ot::<Text>(rng, None, None);
+		test_ot::<List<List<MappableRegister<u64>>>>(rng, None, None);
+		test_ot::<List<u8>>(rng, Some(Validator::max_len(5)), None);
+		test_ot::<Text>(rng, None, Some(Authorizer::new(read_only_client_0)));
+	}
+}
+
+fn read_only_client_0<T, I>(client: usize, _state: &T, _instr: &I) -> Result<(), String> {
+	if client != 0 {
+		Ok(())
+	} else {
+		Err("client 0 is read-only".to_string())
+	}
+}
+
+#[test]
+fn transactions_are_atomic() {
+	let rng = &mut SmallRng::seed_from_u64(0);
+	let (mut clients, mut server) = list_session(None, None);
+
+	let txn = insert_txn(&[1, 2, 3]);
+	clients[0].apply_and_send(txn);
+	assert!(server.try_recv_and_send(rng));
+	assert!(clients[1].try_recv_and_commit());
+	assert_eq!(contents(clients[1].state()), [1, 2, 3]);
+
+	// undo waits for the transaction to be acknowledged
+	assert!(!clients[0].undo_and_send());
+	assert!(clients[0].try_recv_and_commit());
+	assert!(clients[0].undo_and_send());
+	settle(&mut server, &mut clients, rng);
+	assert!(contents(clients[1].state()).is_empty());
+}
+
+#[test]
+fn presence_expires() {
+	let rng = &mut SmallRng::seed_from_u64(0);
+	let (mut clients, mut server) = list_session(None, None);
+
+	clients[0].set_cursor_and_send(Some(<List<u8>>::new().insert(0, 0)));
+	assert!(server.try_recv_and_send(rng));
+	assert!(clients[1].try_recv_and_commit());
+	assert!(clients[1].cursors().contains_key(&0));
+
+	server.expire_presence(0);
+	assert!(clients[1].try_recv_and_commit());
+	assert!(clients[1].cursors().is_empty());
+}
+
+#[test]
+fn invalid_transactions_are_rejected() {
+	let rng = &mut SmallRng::seed_from_u64(0);
+	let (mut clients, mut server) = list_session(Some(Validator::max_len(5)), None);
+
+	clients[0].apply_and_send(insert_txn(&[1, 2, 3]));
+	clients[0].apply_and_send(insert_txn(&[4, 5, 6]));
+	assert_eq!(contents(clients[0].state()), [4, 5, 6, 1, 2, 3]);
+	settle(&mut server, &mut clients, rng);
+	assert!(clients.iter().all(OtClient::drained));
+	assert_eq!(clients[0].rejections(), ["6 elements, at most 5 allowed".to_string()]);
+	assert_eq!(contents(clients[0].state()), [1, 2, 3]);
+	assert_eq!(contents(clients[1].state()), [1, 2, 3]);
+}
+
+#[test]
+fn rejected_transactions_are_not_undone() {
+	let rng = &mut SmallRng::seed_from_u64(0);
+	let (mut clients, mut server) = list_session(Some(Validator::max_len(5)), None);
+
+	clients[0].apply_and_send(insert_txn(&[1, 2, 3]));
+	clients[0].apply_and_send(insert_txn(&[4, 5, 6]));
+	settle(&mut server, &mut clients, rng);
+	assert_eq!(clients[0].rejections().len(), 1);
+	assert_eq!(contents(clients[0].state()), [1, 2, 3]);
+
+	// the rejected transaction is skipped, so the first undo reverts the accepted one
+	assert!(clients[0].undo_and_send());
+	assert!(contents(clients[0].state()).is_empty());
+	assert!(!clients[0].undo_and_send());
+	settle(&mut server, &mut clients, rng);
+	assert!(clients.iter().all(OtClient::drained));
+	assert!(contents(clients[1].state()).is_empty());
+
+	// and redoing it doesn't resubmit the rejected transaction either
+	assert!(clients[0].redo_and_send());
+	assert!(!clients[0].redo_and_send());
+	settle(&mut server, &mut clients, rng);
+	assert!(clients.iter().all(OtClient::drained));
+	assert_eq!(clients[0].rejections().len(), 1);
+	assert_eq!(contents(clients[0].state()), [1, 2, 3]);
+	assert_eq!(contents(clients[1].state()), [1, 2, 3]);
+}
+
+#[test]
+fn unauthorized_transactions_are_rejected() {
+	let rng = &mut SmallRng::seed_from_u64(0);
+	let (mut clients, mut server) = list_session(None, Some(Authorizer::new(read_only_client_0)));
+
+	clients[1].apply_and_send(insert_txn(&[1, 2]));
+	clients[0].apply_and_send(insert_txn(&[3]));
+	settle(&mut server, &mut clients, rng);
+	assert!(clients.iter().all(OtClient::drained));
+	assert_eq!(clients[0].rejections(), ["client 0 is read-only".to_string()]);
+	assert!(clients[1].rejections().is_empty());
+	assert_eq!(contents(clients[0].state()), [1, 2]);
+	assert_eq!(contents(clients[1].state()), [1, 2]);
+}
+
+#[derive(Clone, PartialEq, Eq, State, StateTest, Debug)]
+struct Form {
+	title: Text,
+	body: Text,
+}
+
+/// Lets client 0 edit only the body of a [`Form`], by checking which fields an instruction changes
+fn body_only_for_client_0(client: usize, form: &Form, instr: &<Form as State>::Instr) -> Result<(), String> {
+	let mut form_ = form.clone();
+	form_.apply(instr);
+	if client == 0 && form_.title != form.title {
+		Err("client 0 may only edit the body".to_string())
+	} else {
+		Ok(())
 	}
 }
+
+#[test]
+fn authorization_can_restrict_fields() {
+	let rng = &mut SmallRng::seed_from_u64(0);
+	let start = <Form as StateTest>::gen(rng);
+	let (to_client, from_server): (Vec<_>, Vec<_>) = (0..2).map(|_| channel::<ToClient<<Form as State>::Instr>>()).multiunzip();
+	let (to_server, from_client): (Vec<_>, Vec<_>) = (0..2).map(|_| channel::<ToServer<<Form as State>::Instr>>()).multiunzip();
+	let mut clients =
+		zip((from_server, to_server)).map(|(from_server, to_server)| OtClient::new(start.clone(), from_server, to_server)).collect::<Vec<_>>();
+	let mut server =
+		OtServer::new(zip((to_client, from_client))).with_document(start.clone()).with_authorization(Authorizer::new(body_only_for_client_0));
+
+	// random instructions, until there is one changing only the title and one changing only the body
+	let (mut title_edit, mut body_edit) = (None, None);
+	while title_edit.is_none() || body_edit.is_none() {
+		let instr = StateTest::gen_trivial_instr(&start, rng).unwrap();
+		let mut form = start.clone();
+		form.apply(&instr);
+		if form.title != start.title && form.body == start.body {
+			title_edit = Some(instr);
+		} else if form.body != start.body && form.title == start.title {
+			body_edit = Some(instr);
+		}
+	}
+	clients[0].apply_and_send(vec![body_edit.unwrap()]);
+	clients[0].apply_and_send(vec![title_edit.unwrap()]);
+	settle(&mut server, &mut clients, rng);
+	assert!(clients.iter().all(OtClient::drained));
+	assert_eq!(clients[0].rejections(), ["client 0 may only edit the body".to_string()]);
+	assert!(clients.iter().map(OtClient::state).all_equal());
+	assert!(clients[1].state().title == start.title);
+	assert!(clients[1].state().body != start.body);
+}
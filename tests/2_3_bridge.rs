@@ -1,11 +1,16 @@
 #![allow(clippy::if_not_else, clippy::range_plus_one)]
 
 use itertools::{multizip as zip, Itertools};
-use otto::{list::List, mappable_register::MappableRegister, StateTest};
+use otto::{list::{List, ListInstr}, mappable_register::MappableRegister, StateTest};
 use rand::{prelude::SliceRandom, rngs::SmallRng, Rng, SeedableRng};
 use random_branch::branch_using;
 
-use otto_test::{bridge::CrdtClientOtServer, channel::channel, crdt_client::CrdtClient, ot_client::OtClient};
+mod helpers;
+
+use helpers::{bridge_session, contents, insert_txn, settle_bridge};
+use otto_test::{
+	bridge::CrdtClientOtServer, channel::channel, crdt_client::CrdtClient, ot_client::OtClient, ot_server::{ToClient, ToServer}, validation::{Authorizer, Validator}
+};
 
 fn test_crdt_ot<T: StateTest>(rng: &mut impl Rng) {
 	let clients_crdt = 5;
@@ -13,8 +18,8 @@ fn test_crdt_ot<T: StateTest>(rng: &mut impl Rng) {
 	let mut iters = 50usize;
 	let start = T::gen(rng);
 
-	let (to_client, from_server): (Vec<_>, Vec<_>) = (0..clients_ot + 1).map(|_| channel::<Option<T::Instr>>()).multiunzip();
-	let (to_server, from_client): (Vec<_>, Vec<_>) = (0..clients_ot + 1).map(|_| channel::<Option<T::Instr>>()).multiunzip();
+	let (to_client, from_server): (Vec<_>, Vec<_>) = (0..clients_ot + 1).map(|_| channel::<ToClient<T::Instr>>()).multiunzip();
+	let (to_server, from_client): (Vec<_>, Vec<_>) = (0..clients_ot + 1).map(|_| channel::<ToServer<T::Instr>>()).multiunzip();
 	let crdt_channels = (0..clients_crdt + 1).map(|_| channel()).collect::<Vec<_>>();
 	let mut server = CrdtClientOtServer::<T>::new(
 		start.clone(),
@@ -22,8 +27,13 @@ fn test_crdt_ot<T: StateTest>(rng: &mut impl Rng) {
 		crdt_channels[..crdt_channels.len() - 1].iter().map(|channel| channel.0.clone()),
 		zip((to_client, from_client)),
 	);
-	let mut clients_ot =
-		zip((from_server, to_server)).map(|(from_server, to_server)| OtClient::new(start.clone(), from_server, to_server)).collect::<Vec<_>>();
+	let mut clients_ot = zip((from_server, to_server))
+		.enumerate()
+		.map(|(i, (from_server, to_server))| {
+			let client = OtClient::new(start.clone(), from_server, to_server);
+			if i % 2 == 0 { client.with_undo() } else { client }
+		})
+		.collect::<Vec<_>>();
 
 	let mut clients_crdt = crdt_channels[..crdt_channels.len() - 1]
 		.iter()
@@ -84,6 +94,24 @@ fn test_crdt_ot<T: StateTest>(rng: &mut impl Rng) {
 						continue;
 					}
 				},
+				{
+					if iters == 0 {
+						continue;
+					}
+					let client = clients_ot.choose_mut(rng).unwrap();
+					if !client.undo_and_send() {
+						continue;
+					}
+				},
+				{
+					if iters == 0 {
+						continue;
+					}
+					let client = clients_ot.choose_mut(rng).unwrap();
+					if !client.redo_and_send() {
+						continue;
+					}
+				},
 			});
 		}
 		iters = iters.saturating_sub(1);
@@ -114,3 +142,35 @@ fn fuzz_ot_crdt_short() {
 		test_crdt_ot::<List<List<MappableRegister<u8>>>>(rng);
 	}
 }
+
+#[test]
+fn bridge_rejects_invalid_transactions() {
+	let rng = &mut SmallRng::seed_from_u64(0);
+	let (mut ot_client, mut crdt_client, mut bridge) = bridge_session(|bridge| bridge.with_validation(Validator::max_len(5)));
+
+	ot_client.apply_and_send(insert_txn(&[1, 2, 3]));
+	ot_client.apply_and_send(insert_txn(&[4, 5, 6]));
+	settle_bridge(&mut bridge, &mut ot_client, &mut crdt_client, rng);
+	assert!(ot_client.drained() && crdt_client.drained() && bridge.drained());
+	assert_eq!(ot_client.rejections(), ["6 elements, at most 5 allowed".to_string()]);
+	assert_eq!(contents(ot_client.state()), [1, 2, 3]);
+	assert_eq!(contents(crdt_client.state()), [1, 2, 3]);
+}
+
+#[test]
+fn bridge_rejects_unauthorized_transactions() {
+	let rng = &mut SmallRng::seed_from_u64(0);
+	let (mut ot_client, mut crdt_client, mut bridge) = bridge_session(|bridge| bridge.with_authorization(Authorizer::new(read_only)));
+
+	ot_client.apply_and_send(insert_txn(&[1, 2]));
+	crdt_client.apply_and_send(insert_txn(&[3]));
+	settle_bridge(&mut bridge, &mut ot_client, &mut crdt_client, rng);
+	assert!(ot_client.drained() && crdt_client.drained() && bridge.drained());
+	assert_eq!(ot_client.rejections(), ["OT clients are read-only".to_string()]);
+	assert_eq!(contents(ot_client.state()), [3]);
+	assert_eq!(contents(crdt_client.state()), [3]);
+}
+
+fn read_only(_client: usize, _list: &List<u8>, _instr: &ListInstr<u8>) -> Result<(), String> {
+	Err("OT clients are read-only".to_string())
+}
@@ -0,0 +1,348 @@
+#![allow(clippy::if_not_else, clippy::range_plus_one)]
+
+use itertools::{multizip as zip, Itertools};
+use otto::{list::{List, OttoList}, tuple::OttoTuple2, State, StateTest};
+use rand::{prelude::SliceRandom, rngs::SmallRng, Rng, SeedableRng};
+use random_branch::branch_using;
+
+use otto_test::{
+	bridge::CrdtClientOtServer, channel::{channel, Receiver, Sender}, crdt_client::CrdtClient, diff::Diff, migration::{AddField, Chain, Migration, MigrationRelay}, ot_client::OtClient, ot_server::{OtServer, ToClient, ToServer}
+};
+
+/// Clients on `M::New` talk to the server directly, and clients on `M::Old` through a [`MigrationRelay`]. New clients also edit parts of
+/// the document old ones don't have, so old clients have to converge on the projection of the document new ones converge on.
+fn test_mixed_versions<M>(rng: &mut impl Rng)
+where
+	M: Migration,
+	M::Old: StateTest + Diff,
+	M::New: StateTest,
+{
+	let clients_new = 3;
+	let clients_old = 3;
+	let mut iters = 100usize;
+	let start = <M::Old as StateTest>::gen(rng);
+
+	let (to_client, from_server): (Vec<_>, Vec<_>) =
+		(0..clients_new + clients_old).map(|_| channel::<ToClient<<M::New as State>::Instr>>()).multiunzip();
+	let (to_server, from_client): (Vec<_>, Vec<_>) =
+		(0..clients_new + clients_old).map(|_| channel::<ToServer<<M::New as State>::Instr>>()).multiunzip();
+	let mut server = OtServer::<M::New>::new(zip((to_client, from_client)));
+	let (mut from_server, mut to_server) = (from_server.into_iter(), to_server.into_iter());
+	let mut clients_new = zip((from_server.by_ref().take(clients_new), to_server.by_ref().take(clients_new)))
+		.map(|(from_server, to_server)| OtClient::new(M::upgrade(start.clone()), from_server, to_server))
+		.collect::<Vec<_>>();
+	let (mut clients_old, mut relays) = old_clients::<M>(&start, zip((from_server, to_server)));
+
+	while iters != 0
+		|| !clients_new.iter().all(OtClient::drained)
+		|| !clients_old.iter().all(OtClient::drained)
+		|| !relays.iter().all(MigrationRelay::drained)
+	{
+		loop {
+			break branch_using!(*rng, {
+				{
+					if iters == 0 {
+						continue;
+					}
+					let client = clients_new.choose_mut(rng).unwrap();
+					client.gen_and_send(rng);
+				},
+				{
+					if iters == 0 {
+						continue;
+					}
+					let client = clients_old.choose_mut(rng).unwrap();
+					client.gen_and_send(rng);
+				},
+				{
+					if !server.try_recv_and_send(rng) {
+						continue;
+					}
+				},
+				{
+					let relay = relays.choose_mut(rng).unwrap();
+					if !relay.try_relay_to_server() {
+						continue;
+					}
+				},
+				{
+					let relay = relays.choose_mut(rng).unwrap();
+					if !relay.try_relay_to_client() {
+						continue;
+					}
+				},
+				{
+					let client = clients_new.choose_mut(rng).unwrap();
+					if !client.try_recv_and_commit() {
+						continue;
+					}
+				},
+				{
+					let client = clients_old.choose_mut(rng).unwrap();
+					if !client.try_recv_and_commit() {
+						continue;
+					}
+				},
+				{
+					if iters == 0 {
+						continue;
+					}
+					let client = clients_old.choose_mut(rng).unwrap();
+					if !client.undo_and_send() {
+						continue;
+					}
+				},
+			});
+		}
+		iters = iters.saturating_sub(1);
+	}
+	assert!(clients_new.iter().map(OtClient::state).all_equal());
+	assert!(server.drained());
+	assert!(relays.iter().all(MigrationRelay::drained));
+	let state = M::downgrade(clients_new[0].state().clone());
+	assert!(clients_old.iter().all(|client| *client.state() == state));
+}
+
+/// Like [`test_mixed_versions`], with the relays in front of the OT side of a [`CrdtClientOtServer`] whose CRDT clients are on `M::New`
+fn test_mixed_versions_bridge<M>(rng: &mut impl Rng)
+where
+	M: Migration,
+	M::Old: StateTest + Diff,
+	M::New: StateTest,
+{
+	let clients_crdt = 3;
+	let clients_old = 3;
+	let mut iters = 100usize;
+	let start = <M::Old as StateTest>::gen(rng);
+
+	let (to_client, from_server): (Vec<_>, Vec<_>) = (0..clients_old).map(|_| channel::<ToClient<<M::New as State>::Instr>>()).multiunzip();
+	let (to_server, from_client): (Vec<_>, Vec<_>) = (0..clients_old).map(|_| channel::<ToServer<<M::New as State>::Instr>>()).multiunzip();
+	let crdt_channels = (0..clients_crdt + 1).map(|_| channel()).collect::<Vec<_>>();
+	let mut bridge = CrdtClientOtServer::<M::New>::new(
+		M::upgrade(start.clone()),
+		crdt_channels.last().unwrap().1.clone(),
+		crdt_channels[..clients_crdt].iter().map(|channel| channel.0.clone()),
+		zip((to_client, from_client)),
+	);
+	let mut clients_crdt = crdt_channels[..clients_crdt]
+		.iter()
+		.enumerate()
+		.map(|(i, (_, inbox))| {
+			CrdtClient::new(
+				M::upgrade(start.clone()),
+				inbox.clone(),
+				crdt_channels.iter().enumerate().filter_map(|(i_, (outbox, _))| (i != i_).then(|| outbox.clone())),
+			)
+		})
+		.collect::<Vec<_>>();
+	let (mut clients_old, mut relays) = old_clients::<M>(&start, zip((from_server, to_server)));
+
+	while iters != 0
+		|| !clients_crdt.iter().all(CrdtClient::drained)
+		|| !bridge.drained()
+		|| !clients_old.iter().all(OtClient::drained)
+		|| !relays.iter().all(MigrationRelay::drained)
+	{
+		loop {
+			break branch_using!(*rng, {
+				{
+					if iters == 0 {
+						continue;
+					}
+					let client = clients_crdt.choose_mut(rng).unwrap();
+					client.gen_and_send(rng);
+				},
+				{
+					let client = clients_crdt.choose_mut(rng).unwrap();
+					if !client.try_recv_and_commit() {
+						continue;
+					}
+				},
+				{
+					if !bridge.try_recv_and_send(rng) {
+						continue;
+					}
+				},
+				{
+					if !bridge.try_recv_and_commit() {
+						continue;
+					}
+				},
+				{
+					let relay = relays.choose_mut(rng).unwrap();
+					if !relay.try_relay_to_server() {
+						continue;
+					}
+				},
+				{
+					let relay = relays.choose_mut(rng).unwrap();
+					if !relay.try_relay_to_client() {
+						continue;
+					}
+				},
+				{
+					if iters == 0 {
+						continue;
+					}
+					let client = clients_old.choose_mut(rng).unwrap();
+					client.gen_and_send(rng);
+				},
+				{
+					let client = clients_old.choose_mut(rng).unwrap();
+					if !client.try_recv_and_commit() {
+						continue;
+					}
+				},
+				{
+					if iters == 0 {
+						continue;
+					}
+					let client = clients_old.choose_mut(rng).unwrap();
+					if !client.undo_and_send() {
+						continue;
+					}
+				},
+			});
+		}
+		iters = iters.saturating_sub(1);
+	}
+	assert!(clients_crdt.iter().map(CrdtClient::state).all_equal());
+	let state = M::downgrade(clients_crdt[0].state().clone());
+	assert!(clients_old.iter().all(|client| *client.state() == state));
+}
+
+/// Old clients with undo, each connected to the server over one of `channels` through a relay
+fn old_clients<M>(
+	start: &M::Old, channels: impl Iterator<Item = (Receiver<ToClient<<M::New as State>::Instr>>, Sender<ToServer<<M::New as State>::Instr>>)>,
+) -> (Vec<OtClient<M::Old>>, Vec<MigrationRelay<M>>)
+where
+	M: Migration,
+	M::Old: StateTest + Diff,
+	M::New: StateTest,
+{
+	channels
+		.map(|(from_server, to_server)| {
+			let (to_client_old, from_server_old) = channel();
+			let (to_server_old, from_client_old) = channel();
+			let client = OtClient::new(start.clone(), from_server_old, to_server_old).with_undo();
+			(client, MigrationRelay::new(M::upgrade(start.clone()), (to_client_old, from_client_old), from_server, to_server))
+		})
+		.unzip()
+}
+
+/// Upgrading a snapshot has to commute with applying an instruction
+fn test_upgrade<M>(rng: &mut impl Rng)
+where
+	M: Migration,
+	M::Old: StateTest,
+	M::New: StateTest,
+{
+	let start = <M::Old as StateTest>::gen(rng);
+	let instr = StateTest::gen_trivial_instr(&start, rng).unwrap();
+	let mut old = start.clone();
+	old.apply(&instr);
+
+	let mut new = M::upgrade(start);
+	let instr = M::upgrade_instr(&new, instr);
+	new.apply(&instr);
+	assert!(M::upgrade(old.clone()) == new);
+	assert!(M::downgrade(new) == old);
+}
+
+/// An upgraded instruction has to do to the parts of any document `Old` has what the instruction does to the downgraded document
+fn test_downgrade<M>(rng: &mut impl Rng)
+where
+	M: Migration,
+	M::Old: StateTest,
+	M::New: StateTest,
+{
+	let mut new = <M::New as StateTest>::gen(rng);
+	let mut old = M::downgrade(new.clone());
+	let instr = StateTest::gen_trivial_instr(&old, rng).unwrap();
+	old.apply(&instr);
+	let instr = M::upgrade_instr(&new, instr);
+	new.apply(&instr);
+	assert!(M::downgrade(new) == old);
+}
+
+/// Tags added next to a list
+type AddTags = AddField<List<u8>, List<u8>>;
+
+/// Two fields added over two versions, so that the oldest clients see neither
+type AddTwoFields = Chain<AddField<List<u8>, List<u8>>, AddField<(List<u8>, List<u8>), List<u64>>>;
+
+#[test]
+fn cursors_old_clients_cant_see_keep_their_previous_position() {
+	let rng = &mut SmallRng::seed_from_u64(0);
+	let (to_client, from_server): (Vec<_>, Vec<_>) =
+		(0..2).map(|_| channel::<ToClient<<<AddTags as Migration>::New as State>::Instr>>()).multiunzip();
+	let (to_server, from_client): (Vec<_>, Vec<_>) =
+		(0..2).map(|_| channel::<ToServer<<<AddTags as Migration>::New as State>::Instr>>()).multiunzip();
+	let mut server = OtServer::<<AddTags as Migration>::New>::new(zip((to_client, from_client)));
+	let (mut from_server, mut to_server) = (from_server.into_iter(), to_server.into_iter());
+	let mut client_new = OtClient::new(AddTags::upgrade(List::new()), from_server.next().unwrap(), to_server.next().unwrap());
+	let (to_client_old, from_server_old) = channel();
+	let (to_server_old, from_client_old) = channel();
+	let mut client_old = OtClient::new(List::new(), from_server_old, to_server_old);
+	let mut relay = MigrationRelay::<AddTags>::new(
+		AddTags::upgrade(List::new()),
+		(to_client_old, from_client_old),
+		from_server.next().unwrap(),
+		to_server.next().unwrap(),
+	);
+
+	let state = client_new.state().clone();
+	client_new.set_cursor_and_send(Some(state.map_a(state.0.insert(0, 0))));
+	client_new.set_cursor_and_send(Some(state.map_b(state.1.insert(0, 0))));
+	client_new.apply_and_send(vec![state.map_a(state.0.insert(0, 1))]);
+	let state = client_new.state().clone();
+	client_new.apply_and_send(vec![state.map_b(state.1.insert(0, 2))]);
+	while server.try_recv_and_send(rng)
+		|| relay.try_relay_to_client()
+		|| relay.try_relay_to_server()
+		|| client_new.try_recv_and_commit()
+		|| client_old.try_recv_and_commit()
+	{}
+	assert!(client_old.cursors().contains_key(&0));
+	assert!(server.drained() && relay.drained() && client_new.drained() && client_old.drained());
+	assert_eq!(client_old.state().len(), 1);
+	assert!(AddTags::downgrade(client_new.state().clone()) == *client_old.state());
+}
+
+#[ignore]
+#[test]
+fn fuzz_mixed_versions() {
+	let seed = rand::random();
+	println!("seed: {seed}");
+	let rng = &mut SmallRng::seed_from_u64(seed);
+	for i in 0..u64::MAX {
+		if i % 1_000 == 0 {
+			println!("{}", i);
+		}
+		test_upgrade::<AddTags>(rng);
+		test_upgrade::<AddTwoFields>(rng);
+		test_downgrade::<AddTags>(rng);
+		test_downgrade::<AddTwoFields>(rng);
+		test_mixed_versions::<AddTags>(rng);
+		test_mixed_versions::<AddTwoFields>(rng);
+		test_mixed_versions_bridge::<AddTags>(rng);
+		test_mixed_versions_bridge::<AddTwoFields>(rng);
+	}
+}
+
+#[test]
+fn fuzz_mixed_versions_short() {
+	let seed = rand::random();
+	println!("seed: {seed}");
+	let rng = &mut SmallRng::seed_from_u64(seed);
+	for _ in 0..100 {
+		test_upgrade::<AddTags>(rng);
+		test_upgrade::<AddTwoFields>(rng);
+		test_downgrade::<AddTags>(rng);
+		test_downgrade::<AddTwoFields>(rng);
+		test_mixed_versions::<AddTags>(rng);
+		test_mixed_versions::<AddTwoFields>(rng);
+		test_mixed_versions_bridge::<AddTags>(rng);
+		test_mixed_versions_bridge::<AddTwoFields>(rng);
+	}
+}
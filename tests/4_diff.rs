@@ -0,0 +1,115 @@
+use otto::{crdt::Crdt, list::{List, OttoList}, map::Map, mappable_register::MappableRegister, set::Set, State, StateTest};
+use rand::{Rng, rngs::SmallRng, SeedableRng};
+
+use otto_test::diff::{diff, Diff};
+
+fn test_diff<T: StateTest + Diff + PartialEq>(rng: &mut impl Rng) {
+	let old = <List<T>>::gen(rng);
+	let new = <List<T>>::gen(rng);
+	let mut crdt = Crdt::new(old.clone());
+	for instr in diff(&old, &new) {
+		crdt.apply_(instr);
+	}
+	assert!(*crdt == new);
+	assert!(diff(&new, &new).is_empty());
+}
+
+/// Like [`test_diff`], for states that are diffed as a whole rather than as list elements
+fn test_state_diff<T: StateTest + Diff + PartialEq>(rng: &mut impl Rng) {
+	let old = T::gen(rng);
+	let new = T::gen(rng);
+	let mut crdt = Crdt::new(old.clone());
+	for instr in old.diff(&new).unwrap() {
+		crdt.apply_(instr);
+	}
+	assert!(*crdt == new);
+	assert!(new.diff(&new).unwrap().is_empty());
+}
+
+#[ignore]
+#[test]
+fn fuzz_diff() {
+	let seed = rand::random();
+	println!("seed: {seed}");
+	let rng = &mut SmallRng::seed_from_u64(seed);
+	for i in 0..u64::MAX {
+		if i % 1_000 == 0 {
+			println!("{}", i);
+		}
+		test_diff::<u8>(rng);
+		test_diff::<MappableRegister<u64>>(rng);
+		test_diff::<List<u8>>(rng);
+		test_state_diff::<MappableRegister<u64>>(rng);
+		test_state_diff::<Set<u8>>(rng);
+		test_state_diff::<Map<u8, List<u8>>>(rng);
+		test_state_diff::<(List<u8>, MappableRegister<u64>)>(rng);
+	}
+}
+
+#[test]
+fn fuzz_diff_short() {
+	let seed = rand::random();
+	println!("seed: {seed}");
+	let rng = &mut SmallRng::seed_from_u64(seed);
+	for _ in 0..100 {
+		test_diff::<u8>(rng);
+		test_diff::<MappableRegister<u64>>(rng);
+		test_diff::<List<u8>>(rng);
+		test_state_diff::<MappableRegister<u64>>(rng);
+		test_state_diff::<Set<u8>>(rng);
+		test_state_diff::<Map<u8, List<u8>>>(rng);
+		test_state_diff::<(List<u8>, MappableRegister<u64>)>(rng);
+	}
+}
+
+#[test]
+fn diff_keeps_common_elements() {
+	let old = list(&[1, 2, 3, 4]);
+	let new = list(&[1, 3, 4, 5]);
+	let instrs = diff(&old, &new);
+	assert_eq!(instrs.len(), 2);
+	let mut state = old;
+	instrs.iter().for_each(|instr| state.apply(instr));
+	assert!(state == new);
+}
+
+#[test]
+fn diff_edits_nested_lists_in_place() {
+	let old = nested(&[&[1, 2], &[3]]);
+	let new = nested(&[&[1, 2, 4], &[3]]);
+	let instrs = diff(&old, &new);
+	assert_eq!(instrs.len(), 1);
+
+	// a concurrent edit to the same inner list survives, as the diff edits it rather than replacing it
+	let mut a = Crdt::new(old);
+	let mut b = a.clone();
+	for instr in instrs {
+		a.apply_(instr);
+	}
+	let instr = b.map_at(0, b[0].insert(0, 0));
+	b.apply_(instr);
+	let a_instrs: Vec<_> = a.instrs().collect();
+	let b_instrs: Vec<_> = b.instrs().collect();
+	b.apply_multiple(a_instrs);
+	a.apply_multiple(b_instrs);
+	assert!(*a == nested(&[&[0, 1, 2, 4], &[3]]));
+	assert!(*b == *a);
+}
+
+fn nested(xss: &[&[u8]]) -> List<List<u8>> {
+	let mut list = List::new();
+	for (at, xs) in xss.iter().enumerate() {
+		let instr = list.insert(at, self::list(xs));
+		list.apply(&instr);
+	}
+	list
+}
+
+fn list(xs: &[u8]) -> List<u8> {
+	let mut list = List::new();
+	for (at, &x) in xs.iter().enumerate() {
+		let instr = list.insert(at, x);
+		list.apply(&instr);
+	}
+	list
+}
@@ -0,0 +1,73 @@
+//! Sessions and helpers shared by the simulator tests, each of which uses only some of them
+
+#![allow(dead_code)]
+
+use std::iter;
+
+use itertools::{multizip as zip, Itertools};
+use otto::{list::{List, ListInstr, OttoList}, State, StateTest};
+use rand::Rng;
+
+use otto_test::{
+	bridge::CrdtClientOtServer, channel::channel, crdt_client::CrdtClient, ot_client::OtClient, ot_server::{OtServer, ToClient, ToServer}, validation::{Authorizer, Validator}
+};
+
+/// Two OT clients with undo, connected to a server
+pub fn list_session(
+	validator: Option<Validator<List<u8>>>, authorizer: Option<Authorizer<List<u8>>>,
+) -> (Vec<OtClient<List<u8>>>, OtServer<List<u8>>) {
+	let (to_client, from_server): (Vec<_>, Vec<_>) = (0..2).map(|_| channel::<ToClient<ListInstr<u8>>>()).multiunzip();
+	let (to_server, from_client): (Vec<_>, Vec<_>) = (0..2).map(|_| channel::<ToServer<ListInstr<u8>>>()).multiunzip();
+	let clients =
+		zip((from_server, to_server)).map(|(from_server, to_server)| OtClient::new(List::new(), from_server, to_server).with_undo()).collect();
+	let mut server = OtServer::new(zip((to_client, from_client))).with_document(List::new());
+	if let Some(validator) = validator {
+		server = server.with_validation(validator);
+	}
+	if let Some(authorizer) = authorizer {
+		server = server.with_authorization(authorizer);
+	}
+	(clients, server)
+}
+
+/// One OT client and one CRDT client, connected through a bridge configured by `configure`
+pub fn bridge_session(
+	configure: impl FnOnce(CrdtClientOtServer<List<u8>>) -> CrdtClientOtServer<List<u8>>,
+) -> (OtClient<List<u8>>, CrdtClient<List<u8>>, CrdtClientOtServer<List<u8>>) {
+	let (to_client, from_server) = channel::<ToClient<ListInstr<u8>>>();
+	let (to_server, from_client) = channel::<ToServer<ListInstr<u8>>>();
+	let (to_bridge, bridge_inbox) = channel();
+	let (to_crdt_client, crdt_client_inbox) = channel();
+	let bridge = configure(CrdtClientOtServer::new(List::new(), bridge_inbox, iter::once(to_crdt_client), iter::once((to_client, from_client))));
+	let ot_client = OtClient::new(List::new(), from_server, to_server);
+	let crdt_client = CrdtClient::new(List::new(), crdt_client_inbox, iter::once(to_bridge));
+	(ot_client, crdt_client, bridge)
+}
+
+/// Delivers messages between `server` and `clients` until there are none left
+pub fn settle<T: StateTest>(server: &mut OtServer<T>, clients: &mut [OtClient<T>], rng: &mut impl Rng) {
+	while server.try_recv_and_send(rng) || clients.iter_mut().any(OtClient::try_recv_and_commit) {}
+}
+
+pub fn settle_bridge<T: StateTest>(
+	bridge: &mut CrdtClientOtServer<T>, ot_client: &mut OtClient<T>, crdt_client: &mut CrdtClient<T>, rng: &mut impl Rng,
+) {
+	while bridge.try_recv_and_send(rng) || bridge.try_recv_and_commit() || ot_client.try_recv_and_commit() || crdt_client.try_recv_and_commit() {}
+}
+
+/// Inserts `xs` at the start of a list, one instruction each
+pub fn insert_txn(xs: &[u8]) -> Vec<ListInstr<u8>> {
+	let mut list = <List<u8>>::new();
+	xs.iter()
+		.enumerate()
+		.map(|(at, &x)| {
+			let instr = list.insert(at, x);
+			list.apply(&instr);
+			instr
+		})
+		.collect()
+}
+
+pub fn contents(list: &List<u8>) -> Vec<u8> {
+	(0..list.len()).map(|at| list[at]).collect()
+}